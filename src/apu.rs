@@ -0,0 +1,684 @@
+use gameboy::CLOCK_SPEED;
+use std::io;
+
+// The frame sequencer runs at 512 Hz and clocks the length counters, sweep and envelopes
+const FRAME_SEQUENCER_CYCLES: i64 = 8192;
+
+// Channels are sampled once per M-cycle and averaged down to the output rate
+const SAMPLE_STEP: i64 = 4;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+  [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+  [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+  [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+  [0, 1, 1, 1, 1, 1, 1, 0]  // 75%
+];
+
+const NOISE_DIVISORS: [i64; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Bits that always read back as 1, for 0xFF10 - 0xFF2F. Most of the registers are partially write only
+const READ_MASKS: [u8; 0x20] = [
+  0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10 - NR14
+  0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21 - NR24
+  0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30 - NR34
+  0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41 - NR44
+  0x00, 0x00, 0x70,             // NR50 - NR52
+  0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF // unused
+];
+
+// Mixed left, mixed right, then the four channels on their own
+const STREAMS: usize = 6;
+
+const POWER: u8 = 0b1000_0000;
+const TRIGGER: u8 = 0b1000_0000;
+const LENGTH_ENABLE: u8 = 0b0100_0000;
+
+struct Envelope {
+  volume: u8,
+  increase: bool,
+  period: u8,
+  timer: u8
+}
+
+impl Envelope {
+  fn new() -> Envelope {
+    Envelope {
+      volume: 0,
+      increase: false,
+      period: 0,
+      timer: 0
+    }
+  }
+
+  // NRx2
+  fn trigger(&mut self, register: u8) {
+    self.volume = register >> 4;
+    self.increase = register & 0b1000 != 0;
+    self.period = register & 0b111;
+    self.timer = self.period;
+  }
+
+  fn clock(&mut self) {
+    if self.period == 0 {
+      return;
+    }
+    if self.timer > 0 {
+      self.timer -= 1;
+    }
+    if self.timer == 0 {
+      self.timer = self.period;
+      if self.increase && self.volume < 15 {
+        self.volume += 1;
+      } else if !self.increase && self.volume > 0 {
+        self.volume -= 1;
+      }
+    }
+  }
+}
+
+struct Length {
+  counter: u16,
+  enabled: bool,
+  max: u16
+}
+
+impl Length {
+  fn new(max: u16) -> Length {
+    Length {
+      counter: 0,
+      enabled: false,
+      max: max
+    }
+  }
+
+  fn load(&mut self, value: u8) {
+    self.counter = self.max - value as u16;
+  }
+
+  fn trigger(&mut self) {
+    if self.counter == 0 {
+      self.counter = self.max;
+    }
+  }
+
+  // Returns true if the channel should be switched off
+  fn clock(&mut self) -> bool {
+    if self.enabled && self.counter > 0 {
+      self.counter -= 1;
+      return self.counter == 0;
+    }
+    false
+  }
+}
+
+// Channels 1 and 2
+struct Square {
+  enabled: bool,
+  dac_enabled: bool,
+  duty: u8,
+  duty_position: usize,
+  frequency: u16, // 11 bits
+  timer: i64,
+  length: Length,
+  envelope: Envelope,
+  envelope_register: u8,
+  // Only channel 1 has a sweep unit
+  has_sweep: bool,
+  sweep_register: u8,
+  sweep_enabled: bool,
+  sweep_timer: u8,
+  shadow_frequency: u16
+}
+
+impl Square {
+  fn new(has_sweep: bool) -> Square {
+    Square {
+      enabled: false,
+      dac_enabled: false,
+      duty: 0,
+      duty_position: 0,
+      frequency: 0,
+      timer: 0,
+      length: Length::new(64),
+      envelope: Envelope::new(),
+      envelope_register: 0,
+      has_sweep: has_sweep,
+      sweep_register: 0,
+      sweep_enabled: false,
+      sweep_timer: 0,
+      shadow_frequency: 0
+    }
+  }
+
+  // Register is 0-4 for NRx0 - NRx4
+  fn write(&mut self, register: u16, value: u8) {
+    match register {
+      0 => self.sweep_register = value,
+      1 => {
+        self.duty = value >> 6;
+        self.length.load(value & 0x3f);
+      },
+      2 => {
+        self.envelope_register = value;
+        self.dac_enabled = value & 0xf8 != 0;
+        if !self.dac_enabled {
+          self.enabled = false;
+        }
+      },
+      3 => self.frequency = (self.frequency & 0x700) | value as u16,
+      4 => {
+        self.frequency = (self.frequency & 0xff) | ((value as u16 & 0b111) << 8);
+        self.length.enabled = value & LENGTH_ENABLE != 0;
+        if value & TRIGGER != 0 {
+          self.trigger();
+        }
+      },
+      _ => unreachable!()
+    }
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.dac_enabled;
+    self.length.trigger();
+    self.timer = self.period();
+    self.envelope.trigger(self.envelope_register);
+    if self.has_sweep {
+      self.shadow_frequency = self.frequency;
+      self.sweep_timer = self.sweep_period();
+      self.sweep_enabled = self.sweep_period_bits() != 0 || self.sweep_shift() != 0;
+      if self.sweep_shift() != 0 {
+        self.sweep_calculation();
+      }
+    }
+  }
+
+  fn period(&self) -> i64 {
+    (2048 - self.frequency as i64) * 4
+  }
+
+  fn step(&mut self, cycles: i64) {
+    self.timer -= cycles;
+    while self.timer <= 0 {
+      self.timer += self.period();
+      self.duty_position = (self.duty_position + 1) % 8;
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if self.enabled && DUTY_PATTERNS[self.duty as usize][self.duty_position] == 1 {
+      self.envelope.volume
+    } else {
+      0
+    }
+  }
+
+  fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+
+  fn clock_sweep(&mut self) {
+    if self.sweep_timer > 0 {
+      self.sweep_timer -= 1;
+    }
+    if self.sweep_timer == 0 {
+      self.sweep_timer = self.sweep_period();
+      if self.sweep_enabled && self.sweep_period_bits() != 0 {
+        let frequency = self.sweep_calculation();
+        if frequency <= 2047 && self.sweep_shift() != 0 {
+          self.shadow_frequency = frequency;
+          self.frequency = frequency;
+          // The new frequency is immediately checked for overflow again
+          self.sweep_calculation();
+        }
+      }
+    }
+  }
+
+  // Works out the next frequency, switching the channel off if it would overflow
+  fn sweep_calculation(&mut self) -> u16 {
+    let delta = self.shadow_frequency >> self.sweep_shift();
+    let frequency = if self.sweep_register & 0b1000 != 0 {
+      self.shadow_frequency - delta
+    } else {
+      self.shadow_frequency + delta
+    };
+    if frequency > 2047 {
+      self.enabled = false;
+    }
+    frequency
+  }
+
+  fn sweep_period_bits(&self) -> u8 {
+    (self.sweep_register >> 4) & 0b111
+  }
+
+  // A period of 0 is treated as 8
+  fn sweep_period(&self) -> u8 {
+    match self.sweep_period_bits() {
+      0 => 8,
+      period => period
+    }
+  }
+
+  fn sweep_shift(&self) -> u8 {
+    self.sweep_register & 0b111
+  }
+}
+
+// Channel 3
+struct Wave {
+  enabled: bool,
+  dac_enabled: bool,
+  volume_code: u8,
+  frequency: u16, // 11 bits
+  timer: i64,
+  position: usize,
+  length: Length,
+  // 32 4 bit samples, upper nibble first
+  ram: [u8; 16]
+}
+
+impl Wave {
+  fn new() -> Wave {
+    Wave {
+      enabled: false,
+      dac_enabled: false,
+      volume_code: 0,
+      frequency: 0,
+      timer: 0,
+      position: 0,
+      length: Length::new(256),
+      ram: [0; 16]
+    }
+  }
+
+  // Register is 0-4 for NR30 - NR34
+  fn write(&mut self, register: u16, value: u8) {
+    match register {
+      0 => {
+        self.dac_enabled = value & 0x80 != 0;
+        if !self.dac_enabled {
+          self.enabled = false;
+        }
+      },
+      1 => self.length.load(value),
+      2 => self.volume_code = (value >> 5) & 0b11,
+      3 => self.frequency = (self.frequency & 0x700) | value as u16,
+      4 => {
+        self.frequency = (self.frequency & 0xff) | ((value as u16 & 0b111) << 8);
+        self.length.enabled = value & LENGTH_ENABLE != 0;
+        if value & TRIGGER != 0 {
+          self.enabled = self.dac_enabled;
+          self.length.trigger();
+          self.timer = self.period();
+          self.position = 0;
+        }
+      },
+      _ => unreachable!()
+    }
+  }
+
+  fn period(&self) -> i64 {
+    (2048 - self.frequency as i64) * 2
+  }
+
+  fn step(&mut self, cycles: i64) {
+    self.timer -= cycles;
+    while self.timer <= 0 {
+      self.timer += self.period();
+      self.position = (self.position + 1) % 32;
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if !self.enabled {
+      return 0;
+    }
+    let byte = self.ram[self.position / 2];
+    let sample = if self.position % 2 == 0 {
+      byte >> 4
+    } else {
+      byte & 0x0f
+    };
+    match self.volume_code {
+      0 => 0,
+      1 => sample,
+      2 => sample >> 1,
+      _ => sample >> 2
+    }
+  }
+
+  fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+}
+
+// Channel 4
+struct Noise {
+  enabled: bool,
+  dac_enabled: bool,
+  timer: i64,
+  // 15 bit linear feedback shift register
+  lfsr: u16,
+  polynomial: u8,
+  length: Length,
+  envelope: Envelope,
+  envelope_register: u8
+}
+
+impl Noise {
+  fn new() -> Noise {
+    Noise {
+      enabled: false,
+      dac_enabled: false,
+      timer: 0,
+      lfsr: 0x7fff,
+      polynomial: 0,
+      length: Length::new(64),
+      envelope: Envelope::new(),
+      envelope_register: 0
+    }
+  }
+
+  // Register is 0-4 for NR40 - NR44, NR40 doesn't exist
+  fn write(&mut self, register: u16, value: u8) {
+    match register {
+      0 => (),
+      1 => self.length.load(value & 0x3f),
+      2 => {
+        self.envelope_register = value;
+        self.dac_enabled = value & 0xf8 != 0;
+        if !self.dac_enabled {
+          self.enabled = false;
+        }
+      },
+      3 => self.polynomial = value,
+      4 => {
+        self.length.enabled = value & LENGTH_ENABLE != 0;
+        if value & TRIGGER != 0 {
+          self.enabled = self.dac_enabled;
+          self.length.trigger();
+          self.timer = self.period();
+          self.envelope.trigger(self.envelope_register);
+          self.lfsr = 0x7fff;
+        }
+      },
+      _ => unreachable!()
+    }
+  }
+
+  fn period(&self) -> i64 {
+    NOISE_DIVISORS[(self.polynomial & 0b111) as usize] << (self.polynomial >> 4)
+  }
+
+  fn step(&mut self, cycles: i64) {
+    // Shifts of 14 and 15 stop the LFSR from being clocked at all
+    if self.polynomial >> 4 >= 14 {
+      return;
+    }
+    self.timer -= cycles;
+    while self.timer <= 0 {
+      self.timer += self.period();
+      let bit = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+      self.lfsr = (self.lfsr >> 1) | (bit << 14);
+      // 7 bit mode also feeds back into bit 6, for a shorter more metallic sequence
+      if self.polynomial & 0b1000 != 0 {
+        self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+      }
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if self.enabled && self.lfsr & 1 == 0 {
+      self.envelope.volume
+    } else {
+      0
+    }
+  }
+
+  fn clock_length(&mut self) {
+    if self.length.clock() {
+      self.enabled = false;
+    }
+  }
+}
+
+// Where the APU sends its output, resampled to whatever rate the sink asks for
+pub trait SampleSink {
+  fn sample_rate(&self) -> u32;
+  // Left and right are the final mix, channels are each channel alone before panning and volume.
+  // Everything is between -1 and 1
+  fn push(&mut self, left: f32, right: f32, channels: &[f32; 4]);
+  // Called once emulation is over
+  fn finish(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+// 0xFF10 - 0xFF3F
+pub struct Apu {
+  square_one: Square,
+  square_two: Square,
+  wave: Wave,
+  noise: Noise,
+  // Raw register values, for reading back
+  registers: [u8; 0x20],
+  powered: bool,
+  frame_sequencer_step: u8,
+  frame_sequencer_cycles: i64,
+  output: Option<Box<SampleSink>>,
+  // Running sum of each stream since the last sample went out
+  sums: [f32; STREAMS],
+  sum_cycles: i64,
+  // Emulated cycles scaled by the output sample rate, a sample is due once this reaches CLOCK_SPEED
+  sample_counter: i64,
+  // The output is AC coupled on hardware, which removes the DC offset left over when channels are silent
+  capacitors: [f32; STREAMS]
+}
+
+impl Apu {
+  pub fn new() -> Apu {
+    Apu {
+      square_one: Square::new(true),
+      square_two: Square::new(false),
+      wave: Wave::new(),
+      noise: Noise::new(),
+      registers: [0; 0x20],
+      // Powered off until the boot ROM turns it on
+      powered: false,
+      frame_sequencer_step: 0,
+      frame_sequencer_cycles: 0,
+      output: None,
+      sums: [0.0; STREAMS],
+      sum_cycles: 0,
+      sample_counter: 0,
+      capacitors: [0.0; STREAMS]
+    }
+  }
+
+  pub fn set_output(&mut self, output: Box<SampleSink>) {
+    self.output = Some(output);
+  }
+
+  pub fn finish_output(&mut self) -> io::Result<()> {
+    match self.output {
+      Some(ref mut output) => output.finish(),
+      None => Ok(())
+    }
+  }
+
+  pub fn step(&mut self, cycles: i64) {
+    let mut remaining = cycles;
+    while remaining > 0 {
+      let step = ::std::cmp::min(remaining, SAMPLE_STEP);
+      remaining -= step;
+      if self.powered {
+        self.step_frame_sequencer(step);
+        self.square_one.step(step);
+        self.square_two.step(step);
+        self.wave.step(step);
+        self.noise.step(step);
+      }
+      if self.output.is_some() {
+        self.step_output(step);
+      }
+    }
+  }
+
+  fn step_frame_sequencer(&mut self, cycles: i64) {
+    self.frame_sequencer_cycles += cycles;
+    while self.frame_sequencer_cycles >= FRAME_SEQUENCER_CYCLES {
+      self.frame_sequencer_cycles -= FRAME_SEQUENCER_CYCLES;
+      // Length counters at 256 Hz, sweep at 128 Hz and envelopes at 64 Hz
+      if self.frame_sequencer_step % 2 == 0 {
+        self.square_one.clock_length();
+        self.square_two.clock_length();
+        self.wave.clock_length();
+        self.noise.clock_length();
+      }
+      if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+        self.square_one.clock_sweep();
+      }
+      if self.frame_sequencer_step == 7 {
+        self.square_one.envelope.clock();
+        self.square_two.envelope.clock();
+        self.noise.envelope.clock();
+      }
+      self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+  }
+
+  fn step_output(&mut self, cycles: i64) {
+    let channels = self.channel_outputs();
+    let (left, right) = self.mix(&channels);
+    let streams = [left, right, channels[0], channels[1], channels[2], channels[3]];
+    for (sum, stream) in self.sums.iter_mut().zip(streams.iter()) {
+      *sum += stream * cycles as f32;
+    }
+    self.sum_cycles += cycles;
+    let sample_rate = match self.output {
+      Some(ref output) => output.sample_rate() as i64,
+      None => return
+    };
+    self.sample_counter += cycles * sample_rate;
+    if self.sample_counter >= CLOCK_SPEED {
+      self.sample_counter -= CLOCK_SPEED;
+      // Charge factor of 0.999958 per cycle, the value measured from a DMG
+      let charge = 0.999958f32.powf(CLOCK_SPEED as f32 / sample_rate as f32);
+      let mut samples = [0.0; STREAMS];
+      for i in 0..STREAMS {
+        let average = self.sums[i] / self.sum_cycles as f32;
+        samples[i] = average - self.capacitors[i];
+        self.capacitors[i] = average - samples[i] * charge;
+        self.sums[i] = 0.0;
+      }
+      self.sum_cycles = 0;
+      if let Some(ref mut output) = self.output {
+        output.push(samples[0], samples[1], &[samples[2], samples[3], samples[4], samples[5]]);
+      }
+    }
+  }
+
+  // Returns what each channel's DAC is putting out, between -1 and 1
+  fn channel_outputs(&self) -> [f32; 4] {
+    let mut analog = [0.0; 4];
+    if !self.powered {
+      return analog;
+    }
+    let channels = [
+      (self.square_one.output(), self.square_one.dac_enabled),
+      (self.square_two.output(), self.square_two.dac_enabled),
+      (self.wave.output(), self.wave.dac_enabled),
+      (self.noise.output(), self.noise.dac_enabled)
+    ];
+    for (i, &(output, dac_enabled)) in channels.iter().enumerate() {
+      // Each DAC maps 0 - 15 onto 1 - -1, and puts out nothing when it's off
+      if dac_enabled {
+        analog[i] = 1.0 - output as f32 / 7.5;
+      }
+    }
+    analog
+  }
+
+  // Returns the left and right output, each between -1 and 1
+  fn mix(&self, channels: &[f32; 4]) -> (f32, f32) {
+    let panning = self.registers[0x15];
+    let mut left = 0.0;
+    let mut right = 0.0;
+    for (i, &analog) in channels.iter().enumerate() {
+      if panning & (0x10 << i) != 0 {
+        left += analog;
+      }
+      if panning & (0x01 << i) != 0 {
+        right += analog;
+      }
+    }
+    let volume = self.registers[0x14];
+    let left_volume = ((volume >> 4) & 0b111) as f32 + 1.0;
+    let right_volume = (volume & 0b111) as f32 + 1.0;
+    (left / 4.0 * left_volume / 8.0, right / 4.0 * right_volume / 8.0)
+  }
+
+  pub fn read_byte(&self, address: u16) -> u8 {
+    if address >= 0xFF30 {
+      return self.wave.ram[(address - 0xFF30) as usize];
+    }
+    let index = (address - 0xFF10) as usize;
+    if address == 0xFF26 {
+      let mut value = READ_MASKS[index];
+      if self.powered {
+        value |= POWER;
+      }
+      let statuses = [self.square_one.enabled, self.square_two.enabled, self.wave.enabled, self.noise.enabled];
+      for (i, &enabled) in statuses.iter().enumerate() {
+        if enabled {
+          value |= 1 << i;
+        }
+      }
+      value
+    } else {
+      self.registers[index] | READ_MASKS[index]
+    }
+  }
+
+  pub fn write_byte(&mut self, address: u16, value: u8) {
+    if address >= 0xFF30 {
+      self.wave.ram[(address - 0xFF30) as usize] = value;
+      return;
+    }
+    if address == 0xFF26 {
+      let powered = value & POWER != 0;
+      if self.powered && !powered {
+        // Powering off clears every register
+        for register in 0xFF10..0xFF26 {
+          self.write_register(register, 0);
+        }
+      } else if !self.powered && powered {
+        self.frame_sequencer_step = 0;
+      }
+      self.powered = powered;
+      return;
+    }
+    // Everything else is read only while powered off
+    if self.powered {
+      self.write_register(address, value);
+    }
+  }
+
+  fn write_register(&mut self, address: u16, value: u8) {
+    self.registers[(address - 0xFF10) as usize] = value;
+    if address <= 0xFF14 {
+      self.square_one.write(address - 0xFF10, value);
+    } else if address >= 0xFF15 && address <= 0xFF19 {
+      self.square_two.write(address - 0xFF15, value);
+    } else if address >= 0xFF1A && address <= 0xFF1E {
+      self.wave.write(address - 0xFF1A, value);
+    } else if address >= 0xFF1F && address <= 0xFF23 {
+      self.noise.write(address - 0xFF1F, value);
+    }
+  }
+}
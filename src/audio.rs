@@ -0,0 +1,118 @@
+use bamegoy::apu::SampleSink;
+use cpal;
+use futures::stream::Stream;
+use futures::task::{self, Executor, Run};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// How many samples we let build up before throwing the oldest away, so latency can't grow forever
+// if emulation runs a little fast
+const MAX_BUFFERED_SECONDS: f32 = 0.1;
+
+struct AudioExecutor;
+
+impl Executor for AudioExecutor {
+  fn execute(&self, r: Run) {
+    r.run();
+  }
+}
+
+// Streams stereo samples to the default output device
+pub struct AudioOutput {
+  buffer: Arc<Mutex<VecDeque<(f32, f32)>>>,
+  sample_rate: u32,
+  max_buffered: usize
+}
+
+impl AudioOutput {
+  // Returns None if there is no audio device to play to
+  pub fn new() -> Option<AudioOutput> {
+    let endpoint = match cpal::get_default_endpoint() {
+      Some(endpoint) => endpoint,
+      None => return None
+    };
+    let format = match endpoint.get_supported_formats_list().ok().and_then(|mut formats| formats.next()) {
+      Some(format) => format,
+      None => return None
+    };
+    let event_loop = cpal::EventLoop::new();
+    let (mut voice, stream) = match cpal::Voice::new(&endpoint, &format, &event_loop) {
+      Ok(voice) => voice,
+      Err(_) => return None
+    };
+
+    let buffer = Arc::new(Mutex::new(VecDeque::new()));
+    let consumer = buffer.clone();
+    let channels = format.channels.len();
+    // Repeat the last sample if we run dry, rather than popping
+    let mut last = (0.0, 0.0);
+    task::spawn(stream.for_each(move |output| -> Result<_, ()> {
+      let mut buffer = consumer.lock().unwrap();
+      match output {
+        cpal::UnknownTypeBuffer::U16(mut output) => {
+          for frame in output.chunks_mut(channels) {
+            last = buffer.pop_front().unwrap_or(last);
+            write_frame(frame, last, |value| ((value * 0.5 + 0.5) * ::std::u16::MAX as f32) as u16);
+          }
+        },
+        cpal::UnknownTypeBuffer::I16(mut output) => {
+          for frame in output.chunks_mut(channels) {
+            last = buffer.pop_front().unwrap_or(last);
+            write_frame(frame, last, |value| (value * ::std::i16::MAX as f32) as i16);
+          }
+        },
+        cpal::UnknownTypeBuffer::F32(mut output) => {
+          for frame in output.chunks_mut(channels) {
+            last = buffer.pop_front().unwrap_or(last);
+            write_frame(frame, last, |value| value);
+          }
+        }
+      };
+      Ok(())
+    })).execute(Arc::new(AudioExecutor));
+
+    voice.play();
+    thread::spawn(move || {
+      // The voice stops playing when it is dropped
+      let _voice = voice;
+      event_loop.run();
+    });
+
+    Some(AudioOutput {
+      buffer: buffer,
+      sample_rate: format.samples_rate.0,
+      max_buffered: (format.samples_rate.0 as f32 * MAX_BUFFERED_SECONDS) as usize
+    })
+  }
+}
+
+impl SampleSink for AudioOutput {
+  fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  fn push(&mut self, left: f32, right: f32, _channels: &[f32; 4]) {
+    let mut buffer = self.buffer.lock().unwrap();
+    if buffer.len() >= self.max_buffered {
+      buffer.pop_front();
+    }
+    buffer.push_back((left, right));
+  }
+}
+
+// Left and right go to the first two channels, anything else gets both
+fn write_frame<T, F>(frame: &mut [T], (left, right): (f32, f32), convert: F) where F: Fn(f32) -> T {
+  match frame.len() {
+    1 => frame[0] = convert((left + right) / 2.0),
+    _ => {
+      for (i, out) in frame.iter_mut().enumerate() {
+        *out = match i {
+          0 => convert(left),
+          1 => convert(right),
+          _ => convert((left + right) / 2.0)
+        };
+      }
+    }
+  }
+}
@@ -0,0 +1,73 @@
+use memory::Memory;
+use std::fs::File;
+use std::io::{self, Read};
+
+pub const BOOT_ROM_SIZE: usize = 256;
+pub const CGB_BOOT_ROM_SIZE: usize = 2304;
+
+// What the DMG boot ROM leaves in the I/O registers, in the order we write them.
+// LY and STAT are left to the PPU, which starts at the top of a frame
+const IO_REGISTERS: [(u16, u8); 34] = [
+  (0xFF00, 0x30), // Neither button group selected, reads 0xCF
+  (0xFF01, 0x00),
+  (0xFF02, 0x00),
+  (0xFF05, 0x00),
+  (0xFF06, 0x00),
+  (0xFF07, 0x00),
+  (0xFF26, 0xF1),
+  // The boot chime is left on channel 1 with its envelope run down to nothing. Trigger it silently
+  // and then put the envelope the boot ROM used back, which doesn't take effect until a retrigger
+  (0xFF12, 0x08),
+  (0xFF14, 0x87),
+  (0xFF10, 0x80),
+  (0xFF11, 0xBF),
+  (0xFF12, 0xF3),
+  (0xFF16, 0x3F),
+  (0xFF17, 0x00),
+  (0xFF19, 0x3F),
+  (0xFF1A, 0x7F),
+  (0xFF1B, 0xFF),
+  (0xFF1C, 0x9F),
+  (0xFF1E, 0x3F),
+  (0xFF20, 0xFF),
+  (0xFF21, 0x00),
+  (0xFF22, 0x00),
+  (0xFF23, 0x3F),
+  (0xFF24, 0x77),
+  (0xFF25, 0xF3),
+  (0xFF40, 0x91), // LCD on, background on, tiles at 0x8000
+  (0xFF42, 0x00),
+  (0xFF43, 0x00),
+  (0xFF45, 0x00),
+  (0xFF47, 0xFC),
+  (0xFF48, 0xFF),
+  (0xFF49, 0xFF),
+  (0xFF4A, 0x00),
+  (0xFF4B, 0x00)
+];
+
+// The boot ROM leaves the internal divider here, so DIV reads 0xAB
+const DIVIDER: u16 = 0xABCC;
+
+pub fn load_boot_rom(path: &str) -> Result<Vec<u8>, io::Error> {
+  let mut buf = Vec::new();
+  File::open(path)?.read_to_end(&mut buf)?;
+  if buf.len() != BOOT_ROM_SIZE && buf.len() != CGB_BOOT_ROM_SIZE {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("boot ROM is {} bytes, expected {} or {}", buf.len(), BOOT_ROM_SIZE, CGB_BOOT_ROM_SIZE)));
+  }
+  Ok(buf)
+}
+
+// Puts the hardware in the state the boot ROM would have, for when we don't run one
+pub fn skip_boot_rom(memory: &mut Memory) {
+  for &(address, value) in IO_REGISTERS.iter() {
+    memory.write_byte(address, value);
+  }
+  memory.timer.set_divider(DIVIDER);
+  memory.memory[0xff0f] = 0x01; // VBlank is left pending
+  memory.memory[0xffff] = 0x00;
+  if memory.cgb {
+    // The CGB boot ROM sets every background palette to white
+    memory.bg_palettes.fill(0x7fff);
+  }
+}
@@ -0,0 +1,26 @@
+use memory::Memory;
+use ppu::PPU;
+
+// Everything the CPU drives. The PPU sits beside memory rather than inside it,
+// since stepping it needs the whole of memory
+pub struct Bus {
+  pub memory: Memory,
+  pub ppu: PPU
+}
+
+impl Bus {
+  pub fn new(memory: Memory) -> Bus {
+    Bus {
+      memory: memory,
+      ppu: PPU::new()
+    }
+  }
+
+  // Advances all the hardware by this many CPU cycles
+  pub fn tick(&mut self, cycles: i64) {
+    self.memory.tick(cycles);
+    // The PPU runs at the same speed whatever the CPU is doing
+    let cycles = self.memory.normal_speed_cycles(cycles);
+    self.ppu.tick(&mut self.memory, cycles);
+  }
+}
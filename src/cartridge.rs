@@ -0,0 +1,432 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use rtc::{self, Rtc};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+// MBC2 has 512 half-bytes of RAM built into the controller itself
+const MBC2_RAM_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Controller {
+  None,
+  MBC1,
+  MBC2,
+  MBC3,
+  MBC5
+}
+
+// Bank switching registers, which differ per controller
+enum Banking {
+  None,
+  MBC1 {
+    rom_bank: u8, // 5 bits
+    upper_bits: u8, // 2 bits, either ROM bank bits 5-6 or the RAM bank
+    ram_banking_mode: bool
+  },
+  MBC2 {
+    rom_bank: u8 // 4 bits
+  },
+  MBC3 {
+    rom_bank: u8, // 7 bits
+    ram_bank: u8
+  },
+  MBC5 {
+    rom_bank: u16, // 9 bits
+    ram_bank: u8 // 4 bits
+  }
+}
+
+pub struct Cartridge {
+  rom: Vec<u8>,
+  ram: Vec<u8>,
+  banking: Banking,
+  ram_enabled: bool,
+  rtc: Option<Rtc>,
+  // Where battery backed RAM is persisted, None if the cart has no battery
+  save_path: Option<PathBuf>,
+  ram_dirty: bool
+}
+
+impl Cartridge {
+  pub fn new(mut rom: Vec<u8>, controller: Controller, ram_size: usize) -> Cartridge {
+    // Pad the image out to a whole number of banks (and at least two)
+    // so bank lookups never index out of bounds
+    let rom_size = ::std::cmp::max(rom.len().next_power_of_two(), ROM_BANK_SIZE * 2);
+    rom.resize(rom_size, 0xff);
+    let ram_size = if controller == Controller::MBC2 {
+      MBC2_RAM_SIZE
+    } else {
+      ram_size
+    };
+    let banking = match controller {
+      Controller::None => Banking::None,
+      Controller::MBC1 => Banking::MBC1 { rom_bank: 1, upper_bits: 0, ram_banking_mode: false },
+      Controller::MBC2 => Banking::MBC2 { rom_bank: 1 },
+      Controller::MBC3 => Banking::MBC3 { rom_bank: 1, ram_bank: 0 },
+      Controller::MBC5 => Banking::MBC5 { rom_bank: 1, ram_bank: 0 }
+    };
+    Cartridge {
+      rom: rom,
+      ram: vec![0; ram_size],
+      banking: banking,
+      // Carts without a controller have no way to disable their RAM
+      ram_enabled: controller == Controller::None,
+      rtc: None,
+      save_path: None,
+      ram_dirty: false
+    }
+  }
+
+  pub fn empty() -> Cartridge {
+    Cartridge::new(Vec::new(), Controller::None, 0)
+  }
+
+  // Only MBC3 carts have a clock
+  pub fn add_rtc(&mut self) {
+    if let Banking::MBC3 { .. } = self.banking {
+      self.rtc = Some(Rtc::new());
+    }
+  }
+
+  // Backs the cartridge RAM with a save file, loading it if it already exists.
+  // The file is the raw contents of RAM, the same as every other emulator uses,
+  // followed by the clock state for carts with an RTC
+  pub fn attach_save(&mut self, path: PathBuf) -> Result<(), io::Error> {
+    match File::open(&path) {
+      Ok(mut file) => {
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let mut ram_len = buf.len();
+        if let Some(ref mut rtc) = self.rtc {
+          for trailer_size in &[rtc::SAVE_SIZE, rtc::SHORT_SAVE_SIZE] {
+            if buf.len() == self.ram.len() + trailer_size {
+              ram_len = self.ram.len();
+              rtc.load(&buf[ram_len..]);
+              break;
+            }
+          }
+        }
+        if ram_len != self.ram.len() {
+          warn!("Save file {} is {} bytes but cartridge has {} bytes of RAM, carrying on", path.display(), buf.len(), self.ram.len());
+        }
+        let len = ::std::cmp::min(ram_len, self.ram.len());
+        self.ram[..len].copy_from_slice(&buf[..len]);
+        self.ram_dirty = false;
+      },
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+        // Write out the clock even if the game never touches RAM, so it keeps ticking between sessions
+        self.ram_dirty = self.rtc.is_some();
+      },
+      Err(e) => return Err(e)
+    }
+    self.save_path = Some(path);
+    Ok(())
+  }
+
+  // Writes RAM out to the save file if anything has changed since the last flush
+  pub fn flush_save(&mut self) -> Result<(), io::Error> {
+    if !self.ram_dirty {
+      return Ok(());
+    }
+    if let Some(ref path) = self.save_path {
+      // Write to a temporary file first, so that crashing mid-write can't clobber the old save
+      let temp_path = path.with_extension("sav.tmp");
+      {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&self.ram)?;
+        if let Some(ref mut rtc) = self.rtc {
+          file.write_all(&rtc.save())?;
+        }
+        file.sync_all()?;
+      }
+      fs::rename(&temp_path, path)?;
+    }
+    self.ram_dirty = false;
+    Ok(())
+  }
+
+  // 0x0000 - 0x7FFF
+  pub fn read_rom(&self, address: u16) -> u8 {
+    let bank = if address < 0x4000 {
+      self.lower_rom_bank()
+    } else {
+      self.upper_rom_bank()
+    };
+    let offset = (bank * ROM_BANK_SIZE) & (self.rom.len() - 1);
+    self.rom[offset | (address as usize & (ROM_BANK_SIZE - 1))]
+  }
+
+  // 0x0000 - 0x7FFF
+  // The ROM itself is read only, so writes here are commands to the controller
+  pub fn write_rom(&mut self, address: u16, value: u8) {
+    match self.banking {
+      Banking::None => (),
+      Banking::MBC1 { ref mut rom_bank, ref mut upper_bits, ref mut ram_banking_mode } => {
+        if address < 0x2000 {
+          self.ram_enabled = value & 0x0f == 0x0a;
+        } else if address < 0x4000 {
+          *rom_bank = value & 0x1f;
+          if *rom_bank == 0 {
+            *rom_bank = 1;
+          }
+        } else if address < 0x6000 {
+          *upper_bits = value & 0x03;
+        } else {
+          *ram_banking_mode = value & 0x01 == 0x01;
+        }
+      },
+      Banking::MBC2 { ref mut rom_bank } => {
+        if address < 0x4000 {
+          // The least significant bit of the upper address byte selects the register
+          if address & 0x0100 == 0 {
+            self.ram_enabled = value & 0x0f == 0x0a;
+          } else {
+            *rom_bank = value & 0x0f;
+            if *rom_bank == 0 {
+              *rom_bank = 1;
+            }
+          }
+        }
+      },
+      Banking::MBC3 { ref mut rom_bank, ref mut ram_bank } => {
+        if address < 0x2000 {
+          self.ram_enabled = value & 0x0f == 0x0a;
+        } else if address < 0x4000 {
+          *rom_bank = value & 0x7f;
+          if *rom_bank == 0 {
+            *rom_bank = 1;
+          }
+        } else if address < 0x6000 {
+          *ram_bank = value;
+        } else if let Some(ref mut rtc) = self.rtc {
+          rtc.write_latch(value);
+        }
+      },
+      Banking::MBC5 { ref mut rom_bank, ref mut ram_bank } => {
+        if address < 0x2000 {
+          self.ram_enabled = value & 0x0f == 0x0a;
+        } else if address < 0x3000 {
+          *rom_bank = (*rom_bank & 0x100) | value as u16;
+        } else if address < 0x4000 {
+          *rom_bank = (*rom_bank & 0xff) | ((value as u16 & 0x01) << 8);
+        } else if address < 0x6000 {
+          // Bit 3 drives the rumble motor on rumble carts, which we don't emulate
+          *ram_bank = value & 0x0f;
+        }
+      }
+    }
+  }
+
+  // 0xA000 - 0xBFFF
+  pub fn read_ram(&self, address: u16) -> u8 {
+    if let Some(register) = self.rtc_register() {
+      return match self.rtc {
+        Some(ref rtc) => rtc.read(register),
+        None => 0xff
+      };
+    }
+    match self.ram_address(address) {
+      Some(index) => {
+        if let Banking::MBC2 { .. } = self.banking {
+          // Only the lower nibble is wired up
+          0xf0 | self.ram[index]
+        } else {
+          self.ram[index]
+        }
+      },
+      None => 0xff
+    }
+  }
+
+  // 0xA000 - 0xBFFF
+  pub fn write_ram(&mut self, address: u16, value: u8) {
+    if let Some(register) = self.rtc_register() {
+      if let Some(ref mut rtc) = self.rtc {
+        rtc.write(register, value);
+        self.ram_dirty = true;
+      }
+      return;
+    }
+    if let Some(index) = self.ram_address(address) {
+      self.ram[index] = value;
+      self.ram_dirty = true;
+    }
+  }
+
+  // MBC3 maps the clock registers in place of RAM when banks 0x08 - 0x0C are selected
+  fn rtc_register(&self) -> Option<u8> {
+    match self.banking {
+      Banking::MBC3 { ram_bank, .. } if self.ram_enabled && ram_bank >= 0x08 && ram_bank <= 0x0C => Some(ram_bank),
+      _ => None
+    }
+  }
+
+  fn lower_rom_bank(&self) -> usize {
+    match self.banking {
+      Banking::MBC1 { upper_bits, ram_banking_mode: true, .. } => (upper_bits as usize) << 5,
+      _ => 0
+    }
+  }
+
+  fn upper_rom_bank(&self) -> usize {
+    match self.banking {
+      Banking::None => 1,
+      Banking::MBC1 { rom_bank, upper_bits, .. } => (upper_bits as usize) << 5 | rom_bank as usize,
+      Banking::MBC2 { rom_bank } => rom_bank as usize,
+      Banking::MBC3 { rom_bank, .. } => rom_bank as usize,
+      Banking::MBC5 { rom_bank, .. } => rom_bank as usize
+    }
+  }
+
+  // Translates from a gameboy address to an index into our RAM, if there is RAM there
+  fn ram_address(&self, address: u16) -> Option<usize> {
+    if !self.ram_enabled || self.ram.is_empty() {
+      return None;
+    }
+    let offset = address as usize - 0xA000;
+    let bank = match self.banking {
+      Banking::None => 0,
+      Banking::MBC1 { upper_bits, ram_banking_mode: true, .. } => upper_bits as usize,
+      Banking::MBC1 { .. } => 0,
+      Banking::MBC2 { .. } => {
+        // 512 half-bytes, mirrored throughout the whole range
+        return Some(offset & (MBC2_RAM_SIZE - 1));
+      },
+      Banking::MBC3 { ram_bank, .. } => {
+        if ram_bank > 0x03 {
+          return None;
+        }
+        ram_bank as usize
+      },
+      Banking::MBC5 { ram_bank, .. } => ram_bank as usize
+    };
+    Some((bank * RAM_BANK_SIZE + offset) % self.ram.len())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A ROM where each bank starts with its own number, low byte first
+  fn rom(banks: usize) -> Vec<u8> {
+    let mut rom = vec![0; banks * ROM_BANK_SIZE];
+    for bank in 0..banks {
+      rom[bank * ROM_BANK_SIZE] = bank as u8;
+      rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+    }
+    rom
+  }
+
+  #[test]
+  fn mbc1_rom_bank_zero_selects_bank_one() {
+    let mut cart = Cartridge::new(rom(4), Controller::MBC1, 0);
+    assert_eq!(cart.read_rom(0x4000), 1);
+    cart.write_rom(0x2000, 3);
+    assert_eq!(cart.read_rom(0x4000), 3);
+    cart.write_rom(0x2000, 0);
+    assert_eq!(cart.read_rom(0x4000), 1);
+    assert_eq!(cart.read_rom(0x0000), 0);
+  }
+
+  #[test]
+  fn mbc1_upper_bits() {
+    let mut cart = Cartridge::new(rom(128), Controller::MBC1, 0x8000);
+    cart.write_rom(0x2000, 0x02);
+    cart.write_rom(0x4000, 0x01);
+    assert_eq!(cart.read_rom(0x4000), 0x22);
+    // Only banking mode moves the lower bank, and switches RAM banks
+    assert_eq!(cart.read_rom(0x0000), 0);
+    cart.write_rom(0x6000, 0x01);
+    assert_eq!(cart.read_rom(0x0000), 0x20);
+
+    cart.write_rom(0x0000, 0x0a);
+    cart.write_ram(0xA000, 0x12);
+    cart.write_rom(0x6000, 0x00);
+    assert_eq!(cart.read_ram(0xA000), 0x00);
+    cart.write_rom(0x6000, 0x01);
+    assert_eq!(cart.read_ram(0xA000), 0x12);
+  }
+
+  #[test]
+  fn mbc1_ram_disabled() {
+    let mut cart = Cartridge::new(rom(2), Controller::MBC1, 0x2000);
+    cart.write_ram(0xA000, 0x12);
+    assert_eq!(cart.read_ram(0xA000), 0xff);
+    cart.write_rom(0x0000, 0x0a);
+    cart.write_ram(0xA000, 0x12);
+    assert_eq!(cart.read_ram(0xA000), 0x12);
+    cart.write_rom(0x0000, 0x00);
+    assert_eq!(cart.read_ram(0xA000), 0xff);
+  }
+
+  #[test]
+  fn mbc3_rom_and_ram_banks() {
+    let mut cart = Cartridge::new(rom(128), Controller::MBC3, 0x8000);
+    cart.write_rom(0x2000, 0x7f);
+    assert_eq!(cart.read_rom(0x4000), 0x7f);
+    cart.write_rom(0x2000, 0x00);
+    assert_eq!(cart.read_rom(0x4000), 1);
+
+    cart.write_rom(0x0000, 0x0a);
+    cart.write_rom(0x4000, 0x02);
+    cart.write_ram(0xA000, 0x34);
+    cart.write_rom(0x4000, 0x00);
+    assert_eq!(cart.read_ram(0xA000), 0x00);
+    cart.write_rom(0x4000, 0x02);
+    assert_eq!(cart.read_ram(0xA000), 0x34);
+  }
+
+  #[test]
+  fn mbc3_rtc_registers() {
+    let mut cart = Cartridge::new(rom(2), Controller::MBC3, 0x2000);
+    cart.add_rtc();
+    cart.write_rom(0x0000, 0x0a);
+    // Halt the clock so it can't tick over mid test
+    cart.write_rom(0x4000, 0x0C);
+    cart.write_ram(0xA000, 0x40);
+    cart.write_rom(0x4000, 0x08);
+    cart.write_ram(0xA000, 30);
+    cart.write_rom(0x6000, 0x00);
+    cart.write_rom(0x6000, 0x01);
+    assert_eq!(cart.read_ram(0xA000), 30);
+    // The clock isn't RAM
+    cart.write_rom(0x4000, 0x00);
+    assert_eq!(cart.read_ram(0xA000), 0x00);
+  }
+
+  #[test]
+  fn mbc5_ninth_bit_and_bank_zero() {
+    let mut cart = Cartridge::new(rom(512), Controller::MBC5, 0);
+    cart.write_rom(0x2000, 0x00);
+    assert_eq!(cart.read_rom(0x4000), 0);
+    cart.write_rom(0x3000, 0x01);
+    cart.write_rom(0x2000, 0x05);
+    assert_eq!(cart.read_rom(0x4000), 0x05);
+    assert_eq!(cart.read_rom(0x4001), 0x01);
+    cart.write_rom(0x3000, 0x00);
+    assert_eq!(cart.read_rom(0x4000), 0x05);
+    assert_eq!(cart.read_rom(0x4001), 0x00);
+  }
+
+  #[test]
+  fn mbc5_ram_banks() {
+    let mut cart = Cartridge::new(rom(2), Controller::MBC5, 0x20000);
+    cart.write_rom(0x0000, 0x0a);
+    cart.write_rom(0x4000, 0x0f);
+    cart.write_ram(0xBFFF, 0x56);
+    assert_eq!(cart.ram[0x20000 - 1], 0x56);
+    cart.write_rom(0x4000, 0x00);
+    assert_eq!(cart.read_ram(0xBFFF), 0x00);
+  }
+
+  #[test]
+  fn small_roms_wrap() {
+    let mut cart = Cartridge::new(rom(4), Controller::MBC5, 0);
+    cart.write_rom(0x2000, 0x06);
+    assert_eq!(cart.read_rom(0x4000), 2);
+  }
+}
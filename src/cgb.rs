@@ -0,0 +1,113 @@
+// Game Boy Color only hardware that doesn't need to get at the rest of memory
+
+const AUTO_INCREMENT: u8 = 0b1000_0000;
+
+// Background or sprite colour palette RAM, 8 palettes of 4 colours each.
+// Colours are 15 bit BGR, stored little endian
+pub struct PaletteRam {
+  data: [u8; 64],
+  index: u8,
+  auto_increment: bool
+}
+
+impl PaletteRam {
+  pub fn new() -> PaletteRam {
+    PaletteRam {
+      data: [0; 64],
+      index: 0,
+      auto_increment: false
+    }
+  }
+
+  // BCPS / OCPS
+  pub fn read_spec(&self) -> u8 {
+    let mut value = 0b0100_0000 | self.index;
+    if self.auto_increment {
+      value |= AUTO_INCREMENT;
+    }
+    value
+  }
+
+  // BCPS / OCPS
+  pub fn write_spec(&mut self, value: u8) {
+    self.index = value & 0x3f;
+    self.auto_increment = value & AUTO_INCREMENT == AUTO_INCREMENT;
+  }
+
+  // BCPD / OCPD
+  pub fn read_data(&self) -> u8 {
+    self.data[self.index as usize]
+  }
+
+  // BCPD / OCPD
+  pub fn write_data(&mut self, value: u8) {
+    self.data[self.index as usize] = value;
+    if self.auto_increment {
+      self.index = (self.index + 1) & 0x3f;
+    }
+  }
+
+  // Sets every colour of every palette to the same thing
+  pub fn fill(&mut self, colour: u16) {
+    for entry in self.data.chunks_mut(2) {
+      entry[0] = colour as u8;
+      entry[1] = (colour >> 8) as u8;
+    }
+  }
+
+  pub fn colour(&self, palette: u8, color: u8) -> u16 {
+    let index = (palette as usize * 4 + color as usize) * 2;
+    (self.data[index + 1] as u16) << 8 | self.data[index] as u16
+  }
+}
+
+// 0xFF51 - 0xFF55
+// Copies to VRAM in blocks of 16 bytes, either all at once (general purpose DMA)
+// or a block every HBlank (HBlank DMA)
+pub struct Hdma {
+  pub source: u16,
+  pub destination: u16,
+  pub blocks_left: u8,
+  // Only HBlank DMA is ever left running
+  pub active: bool
+}
+
+impl Hdma {
+  pub fn new() -> Hdma {
+    Hdma {
+      source: 0,
+      destination: 0,
+      blocks_left: 0,
+      active: false
+    }
+  }
+
+  pub fn read_control(&self) -> u8 {
+    if self.active {
+      self.blocks_left - 1
+    } else if self.blocks_left == 0 {
+      0xff
+    } else {
+      // Cancelled, with how much was left
+      0x80 | (self.blocks_left - 1)
+    }
+  }
+
+  pub fn write_byte(&mut self, address: u16, value: u8) {
+    match address {
+      0xFF51 => self.source = (self.source & 0x00ff) | (value as u16) << 8,
+      // The bottom 4 bits are ignored, transfers are always aligned to a block
+      0xFF52 => self.source = (self.source & 0xff00) | (value & 0xf0) as u16,
+      // The destination is always in VRAM
+      0xFF53 => self.destination = (self.destination & 0x00ff) | ((value & 0x1f) as u16) << 8,
+      0xFF54 => self.destination = (self.destination & 0xff00) | (value & 0xf0) as u16,
+      _ => unreachable!()
+    }
+  }
+}
+
+// Scales a 15 bit BGR colour up to 8 bits per channel
+pub fn to_rgb(colour: u16) -> [u8; 3] {
+  let scale = |c: u16| ((c << 3) | (c >> 2)) as u8;
+  [scale(colour & 0x1f), scale((colour >> 5) & 0x1f), scale((colour >> 10) & 0x1f)]
+}
@@ -0,0 +1,23 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+pub const CONFIG_PATH: &'static str = "bamegoy.cfg";
+
+// Reads `key = value` pairs, one per line. Blank lines and lines starting with # are ignored
+pub fn load(path: &str) -> Result<Vec<(String, String)>, io::Error> {
+  let file = File::open(path)?;
+  let mut entries = Vec::new();
+  for line in BufReader::new(file).lines() {
+    let line = line?;
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let mut parts = line.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+      (Some(key), Some(value)) => entries.push((key.trim().to_lowercase(), value.trim().to_string())),
+      _ => println!("Ignoring malformed config line: {}", line)
+    }
+  }
+  Ok(entries)
+}
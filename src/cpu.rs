@@ -1,4 +1,4 @@
-use memory::Memory;
+use bus::Bus;
 use debug::{CB_DEBUG, INSTRUCTION_DEBUG};
 use util::LoHi;
 
@@ -43,11 +43,60 @@ pub struct CPU {
   stack_pointer: u16,
   program_counter: u16,
   transition_enable_interrupts: bool,
-  interrupts: bool // IME
+  interrupts: bool, // IME
+  halted: bool,
+  // HALT with IME off and an interrupt already pending doesn't halt,
+  // but the byte after it is read twice
+  halt_bug: bool,
+  stopped: bool,
+  // Running one of the opcodes that don't exist hangs the CPU until it's reset
+  locked: bool,
+  // Cycles spent on memory accesses so far this instruction, which have already been ticked
+  cycles_ticked: i64
 }
 
 impl CPU {
+  // Everything starts at zero, which is what the boot ROM expects
   pub fn new() -> CPU {
+    CPU {
+      a: 0x00,
+      f: Flags::empty(),
+      b: 0x00,
+      c: 0x00,
+      d: 0x00,
+      e: 0x00,
+      h: 0x00,
+      l: 0x00,
+      stack_pointer: 0x0000,
+      program_counter: 0x0000,
+      transition_enable_interrupts: false,
+      interrupts: false,
+      halted: false,
+      halt_bug: false,
+      stopped: false,
+      locked: false,
+      cycles_ticked: 0
+    }
+  }
+
+  // The registers as the boot ROM leaves them, ready to jump into the cartridge.
+  // Games check for A = 0x11 to know they're on colour hardware
+  pub fn post_boot(cgb: bool) -> CPU {
+    if cgb {
+      return CPU {
+        a: 0x11,
+        f: Flags::from_bits_truncate(0x80),
+        b: 0x00,
+        c: 0x00,
+        d: 0xff,
+        e: 0x56,
+        h: 0x00,
+        l: 0x0d,
+        stack_pointer: 0xfffe,
+        program_counter: 0x100,
+        ..CPU::new()
+      };
+    }
     CPU {
       a: 0x01,
       f: Flags::from_bits_truncate(0xb0),
@@ -59,49 +108,85 @@ impl CPU {
       l: 0x4d,
       stack_pointer: 0xfffe,
       program_counter: 0x100,
-      transition_enable_interrupts: false,
-      interrupts: true
+      ..CPU::new()
     }
   }
 
-  pub fn step(&mut self, memory: &mut Memory) -> i64 {    
+  // Runs an instruction, ticking the rest of the hardware along with every memory access
+  // so that reads and writes land at the right time. Returns how many cycles it took
+  pub fn step(&mut self, bus: &mut Bus) -> i64 {
+    self.cycles_ticked = 0;
+    let cycles = self.execute(bus);
+    debug_assert!(self.cycles_ticked <= cycles);
+    // Whatever wasn't spent on memory accesses was spent working inside the CPU,
+    // which we put at the end of the instruction
+    bus.tick(cycles - self.cycles_ticked);
+    cycles
+  }
+
+  fn execute(&mut self, bus: &mut Bus) -> i64 {
+    // The CPU sits idle while HDMA copies to VRAM
+    let stall_cycles = bus.memory.take_stall_cycles();
+    if stall_cycles > 0 {
+      return stall_cycles;
+    }
+    if self.locked {
+      return 4;
+    }
+    // STOP only wakes up when a button is pressed
+    if self.stopped {
+      if bus.memory.joypad.any_line_low() {
+        self.stopped = false;
+      } else {
+        return 4;
+      }
+    }
     // Interrupts
     {
       let mut active_interrupt: Option<Interrupt> = None;
 
-      let mut ifs = InterruptFlags::from_bits_truncate(memory.read_byte(0xff0f));
-      let ies = InterruptFlags::from_bits_truncate(memory.read_byte(0xffff));
+      let mut ifs = InterruptFlags::from_bits_truncate(bus.memory.read_byte(0xff0f));
+      let ies = InterruptFlags::from_bits_truncate(bus.memory.read_byte(0xffff));
+      let pending = ifs & ies;
 
-      if ifs.contains(VBLANK) && ies.contains(VBLANK) {
+      // HALT wakes up on any pending interrupt, even when interrupts are disabled
+      let mut wake_cycles = 0;
+      if self.halted {
+        if pending.is_empty() {
+          return 4;
+        }
+        self.halted = false;
+        wake_cycles = 4;
+      }
+
+      if pending.contains(VBLANK) {
         active_interrupt = Some(Interrupt::VBlank);
         ifs.remove(VBLANK);
-        memory.write_byte(0xff0f, ifs.bits);
-      } else if ifs.contains(LCD_STAT) && ies.contains(LCD_STAT) {
+      } else if pending.contains(LCD_STAT) {
         active_interrupt = Some(Interrupt::LCDStat);
         ifs.remove(LCD_STAT);
-        memory.write_byte(0xff0f, ifs.bits);
-      } else if ifs.contains(TIMER) && ies.contains(TIMER) {
+      } else if pending.contains(TIMER) {
         active_interrupt = Some(Interrupt::Timer);
         ifs.remove(TIMER);
-        memory.write_byte(0xff0f, ifs.bits);
-      } else if ifs.contains(SERIAL) && ies.contains(SERIAL) {
+      } else if pending.contains(SERIAL) {
         active_interrupt = Some(Interrupt::Serial);
         ifs.remove(SERIAL);
-        memory.write_byte(0xff0f, ifs.bits);
-      } else if ifs.contains(JOYPAD) && ies.contains(JOYPAD) {
+      } else if pending.contains(JOYPAD) {
         active_interrupt = Some(Interrupt::Joypad);
         ifs.remove(JOYPAD);
-        memory.write_byte(0xff0f, ifs.bits);
       }
 
       if self.interrupts {
         if let Some(interrupt) = active_interrupt {
-          println!("Dispatching {:?} interrupt", interrupt);
+          debug!("Dispatching {:?} interrupt", interrupt);
+          // Only acknowledge the interrupt if it's actually serviced
+          bus.memory.write_byte(0xff0f, ifs.bits);
+          self.idle(bus);
           let pc = self.program_counter;
-          self.push_short(memory, pc);
+          self.push_short(bus, pc);
           self.program_counter = interrupt as u16;
           self.interrupts = false;
-          return 80;
+          return 20 + wake_cycles;
         }
       }
 
@@ -111,10 +196,14 @@ impl CPU {
       }
     }
     // Fetch
-    let opcode: u8 = memory.read_byte(self.program_counter);
-    println!("{:02x} ({}) at address {:04x}", opcode, INSTRUCTION_DEBUG[opcode as usize], self.program_counter);
+    let opcode: u8 = self.read_byte(bus, self.program_counter);
+    trace!("{:02x} ({}) at address {:04x}", opcode, INSTRUCTION_DEBUG[opcode as usize], self.program_counter);
     // Increment
-    self.program_counter = self.program_counter.wrapping_add(1);
+    if self.halt_bug {
+      self.halt_bug = false;
+    } else {
+      self.program_counter = self.program_counter.wrapping_add(1);
+    }
     // Execute
     match opcode {
       0x00 => {
@@ -123,13 +212,13 @@ impl CPU {
       },
       0x01 => {
         // LD BC, d16
-        self.c = self.read_byte_immediate(memory);
-        self.b = self.read_byte_immediate(memory);
+        self.c = self.read_byte_immediate(bus);
+        self.b = self.read_byte_immediate(bus);
         12
       },
       0x02 => {
         // LD (BC),A
-        memory.write_byte((self.b as u16) << 8 | self.c as u16, self.a);
+        self.write_byte(bus, (self.b as u16) << 8 | self.c as u16, self.a);
         8
       },
       0x03 => {
@@ -149,13 +238,12 @@ impl CPU {
       },
       0x06 => {
         // LD n into B
-        let value = self.read_byte_immediate(memory);
+        let value = self.read_byte_immediate(bus);
         self.b = value;
         8
       },
       0x07 => {
         // RLCA
-        // TODO vs RLA?
         self.a = self.a.rotate_left(1);
         self.f.remove(ZERO);
         self.f.remove(SUBTRACT);
@@ -165,8 +253,8 @@ impl CPU {
       },
       0x08 => {
         // LD (a16),SP
-        let destination = self.read_short_immediate(memory);
-        memory.write_short(destination, self.stack_pointer);
+        let destination = self.read_short_immediate(bus);
+        self.write_short(bus, destination, self.stack_pointer);
         20
       },
       0x09 => {
@@ -177,7 +265,7 @@ impl CPU {
       },
       0x0a => {
         // LD A,(BC)
-        let value = memory.read_byte(self.bc());
+        let value = self.read_byte(bus, self.bc());
         self.a = value;
         8
       },
@@ -198,13 +286,12 @@ impl CPU {
       },
       0x0e => {
         // LD n into C
-        let value = self.read_byte_immediate(memory);
+        let value = self.read_byte_immediate(bus);
         self.c = value;
         8
       },
       0x0f => {
         // RRCA
-        // TODO this vs RRA?
         self.a = self.a.rotate_right(1);
         self.f.remove(ZERO);
         self.f.remove(SUBTRACT);
@@ -212,15 +299,26 @@ impl CPU {
         self.f.set(CARRY, self.a & 0b1000_0000 == 0b1000_0000);
         4
       },
+      0x10 => {
+        // STOP 0
+        // The second byte is ignored
+        self.program_counter = self.program_counter.wrapping_add(1);
+        bus.memory.write_byte(0xff04, 0);
+        // On CGB, STOP is also how a speed switch asked for through KEY1 happens
+        if !bus.memory.switch_speed() {
+          self.stopped = true;
+        }
+        4
+      },
       0x11 => {
         // LD DE,d16
-        self.e = self.read_byte_immediate(memory);
-        self.d = self.read_byte_immediate(memory);
+        self.e = self.read_byte_immediate(bus);
+        self.d = self.read_byte_immediate(bus);
         12
       },
       0x12 => {
         // LD (DE),A
-        memory.write_byte((self.d as u16) << 8 | self.e as u16, self.a);
+        self.write_byte(bus, (self.d as u16) << 8 | self.e as u16, self.a);
         8
       },
       0x13 => {
@@ -240,22 +338,19 @@ impl CPU {
       },
       0x16 => {
         // LD D,d8
-        self.d = self.read_byte_immediate(memory);
+        self.d = self.read_byte_immediate(bus);
         8
       },
       0x17 => {
         // RLA
-        // TODO: vs RLCA?
-        self.a = self.a.rotate_left(1);
+        // Same as RL A, except Z is always cleared
+        rl_r8(&mut self.a, &mut self.f);
         self.f.remove(ZERO);
-        self.f.remove(SUBTRACT);
-        self.f.remove(HALF_CARRY);
-        self.f.set(CARRY, self.a & 0b0000_0001 == 1);
         4
       },
       0x18 => {
         // JR
-        let rel_target = self.read_signed_byte_immediate(memory);
+        let rel_target = self.read_signed_byte_immediate(bus);
         self.relative_jump(rel_target);
         12
       },
@@ -267,7 +362,7 @@ impl CPU {
       },
       0x1a => {
         // LD A,(DE)
-        self.a = memory.read_byte(self.de());
+        self.a = self.read_byte(bus, self.de());
         8
       },
       0x1b => {
@@ -287,22 +382,19 @@ impl CPU {
       },
       0x1e => {
         // LD E,d8
-        self.e = self.read_byte_immediate(memory);
+        self.e = self.read_byte_immediate(bus);
         8
       },
       0x1f => {
         // RRA
-        // TODO this vs RRCA?
-        self.a = self.a.rotate_right(1);
+        // Same as RR A, except Z is always cleared
+        rr_r8(&mut self.a, &mut self.f);
         self.f.remove(ZERO);
-        self.f.remove(SUBTRACT);
-        self.f.remove(HALF_CARRY);
-        self.f.set(CARRY, self.a & 0b1000_0000 == 0b1000_0000);
         4
       },
       0x20 => {
         // JR NZ
-        let rel_target = self.read_signed_byte_immediate(memory);
+        let rel_target = self.read_signed_byte_immediate(bus);
         if !self.f.contains(ZERO) {
           self.relative_jump(rel_target);
           12
@@ -312,14 +404,14 @@ impl CPU {
       },
       0x21 => {
         // LD nn into HL
-        let value = self.read_short_immediate(memory);
+        let value = self.read_short_immediate(bus);
         self.h = value.hi();
         self.l = value.lo();
         12
       },
       0x22 => {
         // LD (HL+),A
-        memory.write_byte(self.hl(), self.a);
+        self.write_byte(bus, self.hl(), self.a);
         let val = self.hl().wrapping_add(1);
         self.h = val.hi();
         self.l = val.lo();
@@ -342,7 +434,7 @@ impl CPU {
       },
       0x26 => {
         // LD H,d8
-        self.h = self.read_byte_immediate(memory);
+        self.h = self.read_byte_immediate(bus);
         8
       },
       0x27 => {
@@ -366,7 +458,7 @@ impl CPU {
       },
       0x28 => {
         // JR Z,r8
-        let rel_target = self.read_signed_byte_immediate(memory);
+        let rel_target = self.read_signed_byte_immediate(bus);
         if self.f.contains(ZERO) {
           self.relative_jump(rel_target);
           12
@@ -382,7 +474,7 @@ impl CPU {
       },
       0x2a => {
         // LD A,(HL+)
-        self.a = memory.read_byte(self.hl());
+        self.a = self.read_byte(bus, self.hl());
         let val = self.hl().wrapping_add(1);
         self.h = val.hi();
         self.l = val.lo();
@@ -405,7 +497,7 @@ impl CPU {
       },
       0x2e => {
         // LD L,d8
-        self.l = self.read_byte_immediate(memory);
+        self.l = self.read_byte_immediate(bus);
         8
       }
       0x2f => {
@@ -417,7 +509,7 @@ impl CPU {
       },
       0x30 => {
         // JR NC,r8
-        let rel_target = self.read_signed_byte_immediate(memory);
+        let rel_target = self.read_signed_byte_immediate(bus);
         if !self.f.contains(CARRY) {
           self.relative_jump(rel_target);
           12
@@ -427,7 +519,7 @@ impl CPU {
       },
       0x31 => {
         // LD SP,d16
-        let value = self.read_short_immediate(memory);
+        let value = self.read_short_immediate(bus);
         self.stack_pointer = value;
         12
       },
@@ -445,10 +537,10 @@ impl CPU {
       },
       0x34 => {
         // INC (HL)
-        let orig = self.read_byte_immediate(memory);
+        let orig = self.read_byte(bus, self.hl());
         let value = orig.wrapping_add(1);
         let destination = self.hl();
-        memory.write_byte(destination, value);
+        self.write_byte(bus, destination, value);
         self.f.set(ZERO, value == 0);
         self.f.remove(SUBTRACT);
         self.f.set(HALF_CARRY, value & 0x0f < orig & 0x0f);
@@ -456,10 +548,10 @@ impl CPU {
       },
       0x35 => {
         // DEC (HL)
-        let orig = self.read_byte_immediate(memory);
+        let orig = self.read_byte(bus, self.hl());
         let value = orig.wrapping_sub(1);
         let destination = self.hl();
-        memory.write_byte(destination, value);
+        self.write_byte(bus, destination, value);
         self.f.set(ZERO, value == 0);
         self.f.insert(SUBTRACT);
         self.f.set(HALF_CARRY, value & 0x0f > orig & 0x0f);
@@ -467,9 +559,9 @@ impl CPU {
       },
       0x36 => {
         // LD (HL),d8
-        let value = self.read_byte_immediate(memory);
+        let value = self.read_byte_immediate(bus);
         let destination = self.hl();
-        memory.write_byte(destination, value);
+        self.write_byte(bus, destination, value);
         12
       },
       0x37 => {
@@ -481,7 +573,7 @@ impl CPU {
       },
       0x38 => {
         // JR C,r8
-        let rel_target = self.read_signed_byte_immediate(memory);
+        let rel_target = self.read_signed_byte_immediate(bus);
         if self.f.contains(CARRY) {
           self.relative_jump(rel_target);
           12
@@ -497,7 +589,7 @@ impl CPU {
       },
       0x3a => {
         // LD A,(HL-)
-        self.a = memory.read_byte(self.hl());
+        self.a = self.read_byte(bus, self.hl());
         let val = self.hl().wrapping_sub(1);
         self.h = val.hi();
         self.l = val.lo();
@@ -520,7 +612,7 @@ impl CPU {
       },
       0x3e => {
         // LD # into A
-        let result = self.read_byte_immediate(memory);
+        let result = self.read_byte_immediate(bus);
         self.a = result;
         8
       },
@@ -564,7 +656,7 @@ impl CPU {
       },
       0x46 => {
         // LD B,(HL)
-        self.b = memory.read_byte(self.hl());
+        self.b = self.read_byte(bus, self.hl());
         8
       },
       0x47 => {
@@ -604,7 +696,7 @@ impl CPU {
       },
       0x4e => {
         // LD C,(HL)
-        self.c = memory.read_byte(self.hl());
+        self.c = self.read_byte(bus, self.hl());
         8
       },
       0x4f => {
@@ -644,7 +736,7 @@ impl CPU {
       },
       0x56 => {
         // LD D,(HL)
-        self.d = memory.read_byte(self.hl());
+        self.d = self.read_byte(bus, self.hl());
         8
       },
       0x57 => {
@@ -684,7 +776,7 @@ impl CPU {
       },
       0x5e => {
         // LD E,(HL)
-        self.e = memory.read_byte(self.hl());
+        self.e = self.read_byte(bus, self.hl());
         8
       },
       0x5f => {
@@ -722,6 +814,11 @@ impl CPU {
         self.h = self.l;
         4
       },
+      0x66 => {
+        // LD H,(HL)
+        self.h = self.read_byte(bus, self.hl());
+        8
+      },
       0x67 => {
         // LD H,A
         self.h = self.a;
@@ -759,7 +856,7 @@ impl CPU {
       },
       0x6e => {
         // LD L,(HL)
-        self.l = memory.read_byte(self.hl());
+        self.l = self.read_byte(bus, self.hl());
         8
       },
       0x6f => {
@@ -769,37 +866,47 @@ impl CPU {
       },
       0x70 => {
         // LD (HL),B
-        memory.write_byte(self.hl(), self.b);
+        self.write_byte(bus, self.hl(), self.b);
         8
       },
       0x71 => {
         // LD (HL),C
-        memory.write_byte(self.hl(), self.c);
+        self.write_byte(bus, self.hl(), self.c);
         8
       },
       0x72 => {
         // LD (HL),D
-        memory.write_byte(self.hl(), self.d);
+        self.write_byte(bus, self.hl(), self.d);
         8
       },
       0x73 => {
         // LD (HL),E
-        memory.write_byte(self.hl(), self.e);
+        self.write_byte(bus, self.hl(), self.e);
         8
       },
       0x74 => {
         // LD (HL),H
-        memory.write_byte(self.hl(), self.h);
+        self.write_byte(bus, self.hl(), self.h);
         8
       },
       0x75 => {
         // LD (HL),L
-        memory.write_byte(self.hl(), self.l);
+        self.write_byte(bus, self.hl(), self.l);
         8
       },
+      0x76 => {
+        // HALT
+        let pending = bus.memory.read_byte(0xff0f) & bus.memory.read_byte(0xffff) & 0x1f;
+        if !self.interrupts && pending != 0 {
+          self.halt_bug = true;
+        } else {
+          self.halted = true;
+        }
+        4
+      },
       0x77 => {
         // LD (HL),A
-        memory.write_byte(self.hl(), self.a);
+        self.write_byte(bus, self.hl(), self.a);
         8
       },
       0x78 => {
@@ -834,7 +941,7 @@ impl CPU {
       },
       0x7e => {
         // LD A,(HL)
-        self.a = memory.read_byte(self.hl());
+        self.a = self.read_byte(bus, self.hl());
         8
       },
       0x7f => {
@@ -880,7 +987,7 @@ impl CPU {
       },
       0x86 => {
         // ADD A,B
-        let val = memory.read_byte(self.hl());
+        let val = self.read_byte(bus, self.hl());
         self.add_r8(val);
         8
       },
@@ -928,7 +1035,7 @@ impl CPU {
       },
       0x8e => {
         // ADC A,(HL)
-        let val = memory.read_byte(self.hl());
+        let val = self.read_byte(bus, self.hl());
         self.adc_r8(val);
         8
       },
@@ -976,7 +1083,7 @@ impl CPU {
       },
       0x96 => {
         // SUB A,(HL)
-        let val = memory.read_byte(self.hl());
+        let val = self.read_byte(bus, self.hl());
         self.sub_r8(val);
         8
       },
@@ -1024,7 +1131,7 @@ impl CPU {
       },
       0x9e => {
         // SBC A,(HL)
-        let val = memory.read_byte(self.hl());
+        let val = self.read_byte(bus, self.hl());
         self.sbc_r8(val);
         8
       },
@@ -1072,7 +1179,7 @@ impl CPU {
       },
       0xa6 => {
         // AND (HL)
-        let val = memory.read_byte(self.hl());
+        let val = self.read_byte(bus, self.hl());
         self.and_r8(val);
         8
       },
@@ -1120,7 +1227,7 @@ impl CPU {
       },
       0xae => {
         // XOR (HL)
-        let val = memory.read_byte(self.hl());
+        let val = self.read_byte(bus, self.hl());
         self.xor_r8(val);
         8
       },
@@ -1168,7 +1275,7 @@ impl CPU {
       },
       0xb6 => {
         // OR (HL)
-        let val = memory.read_byte(self.hl());
+        let val = self.read_byte(bus, self.hl());
         self.or_r8(val);
         8
       },
@@ -1216,7 +1323,7 @@ impl CPU {
       },
       0xbe => {
         // CP (HL)
-        let val = memory.read_byte(self.hl());
+        let val = self.read_byte(bus, self.hl());
         self.cp_r8(val);
         8
       },
@@ -1229,7 +1336,7 @@ impl CPU {
       0xc0 => {
         // RET NZ
         if !self.f.contains(ZERO) {
-          self.program_counter = self.pop_short(memory);
+          self.program_counter = self.pop_short(bus);
           20
         } else {
           8
@@ -1237,13 +1344,13 @@ impl CPU {
       },
       0xc1 => {
         // POP BC
-        self.c = self.pop_byte(memory);
-        self.b = self.pop_byte(memory);
+        self.c = self.pop_byte(bus);
+        self.b = self.pop_byte(bus);
         12
       },
       0xc2 => {
         // JP NZ,a16
-        let destination = self.read_short_immediate(memory);
+        let destination = self.read_short_immediate(bus);
         if !self.f.contains(ZERO) {
           self.program_counter = destination;
           16
@@ -1253,16 +1360,16 @@ impl CPU {
       },
       0xc3 => {
         // JP a16
-        let target = memory.read_short(self.program_counter);
+        let target = self.read_short(bus, self.program_counter);
         self.program_counter = target;
         16
       },
       0xc4 => {
         // CALL NZ,a16
-        let target = self.read_short_immediate(memory);
+        let target = self.read_short_immediate(bus);
         if !self.f.contains(ZERO) {
           let pc = self.program_counter;
-          self.push_short(memory, pc);
+          self.push_short(bus, pc);
           self.program_counter = target;
           24
         } else {
@@ -1271,27 +1378,28 @@ impl CPU {
       },
       0xc5 => {
         // PUSH BC
+        self.idle(bus);
         let b = self.b;
-        self.push_byte(memory, b);
+        self.push_byte(bus, b);
         let c = self.c;
-        self.push_byte(memory, c);
+        self.push_byte(bus, c);
         16
       },
       0xc6 => {
         // ADD A,d8
-        let value = self.read_byte_immediate(memory);
+        let value = self.read_byte_immediate(bus);
         self.add_r8(value);
         8
       },
       0xc7 => {
         // RST 00H
-        self.rst(0x0000, memory);
+        self.rst(0x0000, bus);
         16
       },
       0xc8 => {
         // RET Z
         if self.f.contains(ZERO) {
-          self.program_counter = self.pop_short(memory);
+          self.program_counter = self.pop_short(bus);
           20
         } else {
           8
@@ -1299,12 +1407,12 @@ impl CPU {
       },
       0xc9 => {
         // RET
-        self.program_counter = self.pop_short(memory);
+        self.program_counter = self.pop_short(bus);
         16
       },
       0xca => {
         // JP Z,a16
-        let target = self.read_short_immediate(memory);
+        let target = self.read_short_immediate(bus);
         if self.f.contains(ZERO) {
           self.program_counter = target;
           16
@@ -1315,15 +1423,15 @@ impl CPU {
       0xcb => {
         // CB
         // TODO we could make this more granular and return 4 immediately here, then execute instructions next step
-        let next_opcode = self.read_byte_immediate(memory);
-        self.cb(next_opcode)
+        let next_opcode = self.read_byte_immediate(bus);
+        self.cb(next_opcode, bus)
       },
       0xcc => {
         // CALL Z,a16
-        let target = self.read_short_immediate(memory);
+        let target = self.read_short_immediate(bus);
         if self.f.contains(ZERO) {
           let pc = self.program_counter;
-          self.push_short(memory, pc);
+          self.push_short(bus, pc);
           self.program_counter = target;
           24
         } else {
@@ -1332,27 +1440,27 @@ impl CPU {
       },
       0xcd => {
         // CALL a16
-        let target = self.read_short_immediate(memory);
+        let target = self.read_short_immediate(bus);
         let pc = self.program_counter;
-        self.push_short(memory, pc);
+        self.push_short(bus, pc);
         self.program_counter = target;
         24
       },
       0xce => {
         // ADC A,d8
-        let value = self.read_byte_immediate(memory);
+        let value = self.read_byte_immediate(bus);
         self.adc_r8(value);
         8
       },
       0xcf => {
         // RST 08H
-        self.rst(0x0008, memory);
+        self.rst(0x0008, bus);
         16
       },
       0xd0 => {
         // RET NC
         if !self.f.contains(CARRY) {
-          self.program_counter = self.pop_short(memory);
+          self.program_counter = self.pop_short(bus);
           20
         } else {
           8
@@ -1360,13 +1468,13 @@ impl CPU {
       },
       0xd1 => {
         // POP DE
-        self.e = self.pop_byte(memory);
-        self.d = self.pop_byte(memory);
+        self.e = self.pop_byte(bus);
+        self.d = self.pop_byte(bus);
         12
       },
       0xd2 => {
         // JP NC,a16
-        let destination = self.read_short_immediate(memory);
+        let destination = self.read_short_immediate(bus);
         if !self.f.contains(CARRY) {
           self.program_counter = destination;
           16
@@ -1376,10 +1484,10 @@ impl CPU {
       },
       0xd4 => {
         // CALL NC,a16
-        let target = self.read_short_immediate(memory);
+        let target = self.read_short_immediate(bus);
         if !self.f.contains(CARRY) {
           let pc = self.program_counter;
-          self.push_short(memory, pc);
+          self.push_short(bus, pc);
           self.program_counter = target;
           24
         } else {
@@ -1388,27 +1496,28 @@ impl CPU {
       }
       0xd5 => {
         // PUSH DE
+        self.idle(bus);
         let d = self.d;
-        self.push_byte(memory, d);
+        self.push_byte(bus, d);
         let e = self.e;
-        self.push_byte(memory, e);
+        self.push_byte(bus, e);
         16
       },
       0xd6 => {
         // SUB d8
-        let value = self.read_byte_immediate(memory);
+        let value = self.read_byte_immediate(bus);
         self.sub_r8(value);
         8
       },
       0xd7 => {
         // RST 10H
-        self.rst(0x0010, memory);
+        self.rst(0x0010, bus);
         16
       },
       0xd8 => {
         // RET C
         if self.f.contains(CARRY) {
-          self.program_counter = self.pop_short(memory);
+          self.program_counter = self.pop_short(bus);
           20
         } else {
           8
@@ -1416,14 +1525,14 @@ impl CPU {
       },
       0xd9 => {
         // RETI
-        self.program_counter = self.pop_short(memory);
+        self.program_counter = self.pop_short(bus);
         // TODO: does this have a delay?
         self.transition_enable_interrupts = true;
         16
       },
       0xda => {
         // JP C,a16
-        let destination = self.read_short_immediate(memory);
+        let destination = self.read_short_immediate(bus);
         if self.f.contains(CARRY) {
           self.program_counter = destination;
           16
@@ -1433,10 +1542,10 @@ impl CPU {
       },
       0xdc => {
         // CALL C,a16
-        let target = self.read_short_immediate(memory);
+        let target = self.read_short_immediate(bus);
         if self.f.contains(CARRY) {
           let pc = self.program_counter;
-          self.push_short(memory, pc);
+          self.push_short(bus, pc);
           self.program_counter = target;
           24
         } else {
@@ -1445,60 +1554,56 @@ impl CPU {
       },
       0xde => {
         // SBC A,d8
-        let val = self.read_byte_immediate(memory);
+        let val = self.read_byte_immediate(bus);
         self.sbc_r8(val);
         8
       },
       0xdf => {
         // RST 18H
-        self.rst(0x0018, memory);
+        self.rst(0x0018, bus);
         16
       },
       0xe0 => {
         // LDH n,A
-        let offset = self.read_byte_immediate(memory);
-        memory.write_byte(0xFF00 + offset as u16, self.a);
+        let offset = self.read_byte_immediate(bus);
+        self.write_byte(bus, 0xFF00 + offset as u16, self.a);
         12
       },
       0xe1 => {
         // POP HL
-        self.l = self.pop_byte(memory);
-        self.h = self.pop_byte(memory);
+        self.l = self.pop_byte(bus);
+        self.h = self.pop_byte(bus);
         12
       },
       0xe2 => {
         // LD (C),A
-        memory.write_byte(0xFF00 + self.c as u16, self.a);
+        self.write_byte(bus, 0xFF00 + self.c as u16, self.a);
         8
       },
       0xe5 => {
         // PUSH HL
+        self.idle(bus);
         let h = self.h;
-        self.push_byte(memory, h);
+        self.push_byte(bus, h);
         let l = self.l;
-        self.push_byte(memory, l);
+        self.push_byte(bus, l);
         16
       },
       0xe6 => {
         // AND d8
-        let val = self.read_byte_immediate(memory);
+        let val = self.read_byte_immediate(bus);
         self.and_r8(val);
         8
       },
       0xe7 => {
         // RST 20H
-        self.rst(0x0020, memory);
+        self.rst(0x0020, bus);
         16
       },
       0xe8 => {
         // ADD SP,r8
-        let orig = self.stack_pointer;
-        let val = self.read_byte_immediate(memory);
-        self.stack_pointer = self.stack_pointer.wrapping_add(val as u16);
-        self.f.remove(ZERO);
-        self.f.remove(SUBTRACT);
-        self.f.set(HALF_CARRY, self.stack_pointer & 0xff < orig & 0xff);
-        self.f.set(CARRY, self.stack_pointer < orig);
+        let offset = self.read_signed_byte_immediate(bus);
+        self.stack_pointer = self.sp_plus_offset(offset);
         16
       },
       0xe9 => {
@@ -1508,33 +1613,38 @@ impl CPU {
       },
       0xea => {
         // LD a16,A
-        let dest = self.read_short_immediate(memory);
-        memory.write_byte(dest, self.a);
+        let dest = self.read_short_immediate(bus);
+        self.write_byte(bus, dest, self.a);
         16
       },
       0xee => {
         // XOR d8
-        let val = self.read_byte_immediate(memory);
+        let val = self.read_byte_immediate(bus);
         self.xor_r8(val);
         8
       },
       0xef => {
         // RST 28H
-        self.rst(0x0028, memory);
+        self.rst(0x0028, bus);
         16
       },
       0xf0 => {
         // LDH A,n
-        let offset = self.read_byte_immediate(memory);
-        self.a = memory.read_byte(0xFF00 + offset as u16);
+        let offset = self.read_byte_immediate(bus);
+        self.a = self.read_byte(bus, 0xFF00 + offset as u16);
         12
       },
       0xf1 => {
         // POP AF
-        self.f = Flags::from_bits_truncate(self.pop_byte(memory));
-        self.a = self.pop_byte(memory);
+        self.f = Flags::from_bits_truncate(self.pop_byte(bus));
+        self.a = self.pop_byte(bus);
         12
       },
+      0xf2 => {
+        // LD A,(C)
+        self.a = self.read_byte(bus, 0xFF00 + self.c as u16);
+        8
+      },
       0xf3 => {
         // DI
         self.interrupts = false;
@@ -1543,20 +1653,28 @@ impl CPU {
       0xf5 => {
         // PSH AF
         let af = self.af();
-        self.push_short(memory, af);
+        self.push_short(bus, af);
         16
       },
       0xf6 => {
         // OR d8
-        let val = self.read_byte_immediate(memory);
+        let val = self.read_byte_immediate(bus);
         self.or_r8(val);
         8
       },
       0xf7 => {
         // RST 30H
-        self.rst(0x0030, memory);
+        self.rst(0x0030, bus);
         16
       },
+      0xf8 => {
+        // LD HL,SP+r8
+        let offset = self.read_signed_byte_immediate(bus);
+        let value = self.sp_plus_offset(offset);
+        self.h = value.hi();
+        self.l = value.lo();
+        12
+      },
       0xf9 => {
         // LD SP,HL
         self.stack_pointer = self.hl();
@@ -1564,8 +1682,8 @@ impl CPU {
       },
       0xfa => {
         // LD A,(a16)
-        let addr = self.read_short_immediate(memory);
-        self.a = memory.read_byte(addr);
+        let addr = self.read_short_immediate(bus);
+        self.a = self.read_byte(bus, addr);
         16
       },
       0xfb => {
@@ -1575,24 +1693,110 @@ impl CPU {
       },
       0xfe => {
         // CP n
-        let value = self.read_byte_immediate(memory);
+        let value = self.read_byte_immediate(bus);
         self.cp_r8(value);
         8
       },
       0xff => {
         // RST 38H
-        self.rst(0x0038, memory);
+        self.rst(0x0038, bus);
         16
       },
-      _ => {
-        unimplemented!()
+      0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+        warn!("Illegal opcode {:02x} at address {:04x}, locking up", opcode, self.program_counter.wrapping_sub(1));
+        self.locked = true;
+        4
       }
     }
   }
 
-  fn cb(&mut self, opcode: u8) -> i64 {
-    println!("cb {:02x} ({})", opcode, CB_DEBUG[opcode as usize]);
+  fn cb(&mut self, opcode: u8, bus: &mut Bus) -> i64 {
+    trace!("cb {:02x} ({})", opcode, CB_DEBUG[opcode as usize]);
     match opcode {
+      0x00 => {
+        // RLC B
+        rlc_r8(&mut self.b, &mut self.f);
+        8
+      },
+      0x01 => {
+        // RLC C
+        rlc_r8(&mut self.c, &mut self.f);
+        8
+      },
+      0x02 => {
+        // RLC D
+        rlc_r8(&mut self.d, &mut self.f);
+        8
+      },
+      0x03 => {
+        // RLC E
+        rlc_r8(&mut self.e, &mut self.f);
+        8
+      },
+      0x04 => {
+        // RLC H
+        rlc_r8(&mut self.h, &mut self.f);
+        8
+      },
+      0x05 => {
+        // RLC L
+        rlc_r8(&mut self.l, &mut self.f);
+        8
+      },
+      0x06 => {
+        // RLC (HL)
+        let mut value = self.read_byte(bus, self.hl());
+        rlc_r8(&mut value, &mut self.f);
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
+      0x07 => {
+        // RLC A
+        rlc_r8(&mut self.a, &mut self.f);
+        8
+      },
+      0x08 => {
+        // RRC B
+        rrc_r8(&mut self.b, &mut self.f);
+        8
+      },
+      0x09 => {
+        // RRC C
+        rrc_r8(&mut self.c, &mut self.f);
+        8
+      },
+      0x0a => {
+        // RRC D
+        rrc_r8(&mut self.d, &mut self.f);
+        8
+      },
+      0x0b => {
+        // RRC E
+        rrc_r8(&mut self.e, &mut self.f);
+        8
+      },
+      0x0c => {
+        // RRC H
+        rrc_r8(&mut self.h, &mut self.f);
+        8
+      },
+      0x0d => {
+        // RRC L
+        rrc_r8(&mut self.l, &mut self.f);
+        8
+      },
+      0x0e => {
+        // RRC (HL)
+        let mut value = self.read_byte(bus, self.hl());
+        rrc_r8(&mut value, &mut self.f);
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
+      0x0f => {
+        // RRC A
+        rrc_r8(&mut self.a, &mut self.f);
+        8
+      },
       0x10 => {
         // RL B
         rl_r8(&mut self.b, &mut self.f);
@@ -1610,7 +1814,7 @@ impl CPU {
       },
       0x13 => {
         // RL E
-        rl_r8(&mut self.d, &mut self.f);
+        rl_r8(&mut self.e, &mut self.f);
         8
       },
       0x14 => {
@@ -1623,6 +1827,13 @@ impl CPU {
         rl_r8(&mut self.l, &mut self.f);
         8
       },
+      0x16 => {
+        // RL (HL)
+        let mut value = self.read_byte(bus, self.hl());
+        rl_r8(&mut value, &mut self.f);
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0x17 => {
         // RL A
         rl_r8(&mut self.a, &mut self.f);
@@ -1658,11 +1869,102 @@ impl CPU {
         rr_r8(&mut self.l, &mut self.f);
         8
       },
+      0x1e => {
+        // RR (HL)
+        let mut value = self.read_byte(bus, self.hl());
+        rr_r8(&mut value, &mut self.f);
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0x1f => {
         // RR A
         rr_r8(&mut self.a, &mut self.f);
         8
       }
+      0x20 => {
+        // SLA B
+        sla_r8(&mut self.b, &mut self.f);
+        8
+      },
+      0x21 => {
+        // SLA C
+        sla_r8(&mut self.c, &mut self.f);
+        8
+      },
+      0x22 => {
+        // SLA D
+        sla_r8(&mut self.d, &mut self.f);
+        8
+      },
+      0x23 => {
+        // SLA E
+        sla_r8(&mut self.e, &mut self.f);
+        8
+      },
+      0x24 => {
+        // SLA H
+        sla_r8(&mut self.h, &mut self.f);
+        8
+      },
+      0x25 => {
+        // SLA L
+        sla_r8(&mut self.l, &mut self.f);
+        8
+      },
+      0x26 => {
+        // SLA (HL)
+        let mut value = self.read_byte(bus, self.hl());
+        sla_r8(&mut value, &mut self.f);
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
+      0x27 => {
+        // SLA A
+        sla_r8(&mut self.a, &mut self.f);
+        8
+      },
+      0x28 => {
+        // SRA B
+        sra_r8(&mut self.b, &mut self.f);
+        8
+      },
+      0x29 => {
+        // SRA C
+        sra_r8(&mut self.c, &mut self.f);
+        8
+      },
+      0x2a => {
+        // SRA D
+        sra_r8(&mut self.d, &mut self.f);
+        8
+      },
+      0x2b => {
+        // SRA E
+        sra_r8(&mut self.e, &mut self.f);
+        8
+      },
+      0x2c => {
+        // SRA H
+        sra_r8(&mut self.h, &mut self.f);
+        8
+      },
+      0x2d => {
+        // SRA L
+        sra_r8(&mut self.l, &mut self.f);
+        8
+      },
+      0x2e => {
+        // SRA (HL)
+        let mut value = self.read_byte(bus, self.hl());
+        sra_r8(&mut value, &mut self.f);
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
+      0x2f => {
+        // SRA A
+        sra_r8(&mut self.a, &mut self.f);
+        8
+      },
       0x30 => {
         // SWAP B
         swap_r8(&mut self.b, &mut self.f);
@@ -1693,6 +1995,13 @@ impl CPU {
         swap_r8(&mut self.l, &mut self.f);
         8
       },
+      0x36 => {
+        // SWAP (HL)
+        let mut value = self.read_byte(bus, self.hl());
+        swap_r8(&mut value, &mut self.f);
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0x37 => {
         // SWAP A
         swap_r8(&mut self.a, &mut self.f);
@@ -1700,12 +2009,44 @@ impl CPU {
       },
       0x38 => {
         // SRL B
-        let orig = self.b;
-        self.b >>= 1;
-        self.f.set(ZERO, self.b == 0);
-        self.f.remove(SUBTRACT);
-        self.f.remove(HALF_CARRY);
-        self.f.set(CARRY, orig & 0b0000_0001 == 1);
+        srl_r8(&mut self.b, &mut self.f);
+        8
+      },
+      0x39 => {
+        // SRL C
+        srl_r8(&mut self.c, &mut self.f);
+        8
+      },
+      0x3a => {
+        // SRL D
+        srl_r8(&mut self.d, &mut self.f);
+        8
+      },
+      0x3b => {
+        // SRL E
+        srl_r8(&mut self.e, &mut self.f);
+        8
+      },
+      0x3c => {
+        // SRL H
+        srl_r8(&mut self.h, &mut self.f);
+        8
+      },
+      0x3d => {
+        // SRL L
+        srl_r8(&mut self.l, &mut self.f);
+        8
+      },
+      0x3e => {
+        // SRL (HL)
+        let mut value = self.read_byte(bus, self.hl());
+        srl_r8(&mut value, &mut self.f);
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
+      0x3f => {
+        // SRL A
+        srl_r8(&mut self.a, &mut self.f);
         8
       },
       0x40 => {
@@ -1744,6 +2085,12 @@ impl CPU {
         self.test_bit_at_r8(val, 0);
         8
       },
+      0x46 => {
+        // BIT 0,(HL)
+        let val = self.read_byte(bus, self.hl());
+        self.test_bit_at_r8(val, 0);
+        12
+      },
       0x47 => {
         // BIT 0,A
         let val = self.a;
@@ -1786,6 +2133,12 @@ impl CPU {
         self.test_bit_at_r8(val, 1);
         8
       },
+      0x4e => {
+        // BIT 1,(HL)
+        let val = self.read_byte(bus, self.hl());
+        self.test_bit_at_r8(val, 1);
+        12
+      },
       0x4f => {
         // BIT 1,A
         let val = self.a;
@@ -1828,6 +2181,12 @@ impl CPU {
         self.test_bit_at_r8(val, 2);
         8
       },
+      0x56 => {
+        // BIT 2,(HL)
+        let val = self.read_byte(bus, self.hl());
+        self.test_bit_at_r8(val, 2);
+        12
+      },
       0x57 => {
         // BIT 2,A
         let val = self.a;
@@ -1870,6 +2229,12 @@ impl CPU {
         self.test_bit_at_r8(val, 3);
         8
       },
+      0x5e => {
+        // BIT 3,(HL)
+        let val = self.read_byte(bus, self.hl());
+        self.test_bit_at_r8(val, 3);
+        12
+      },
       0x5f => {
         // BIT 3,A
         let val = self.a;
@@ -1912,9 +2277,15 @@ impl CPU {
         self.test_bit_at_r8(val, 4);
         8
       },
+      0x66 => {
+        // BIT 4,(HL)
+        let val = self.read_byte(bus, self.hl());
+        self.test_bit_at_r8(val, 4);
+        12
+      },
       0x67 => {
         // BIT 4,A
-        let val = self.b;
+        let val = self.a;
         self.test_bit_at_r8(val, 4);
         8
       },
@@ -1925,20 +2296,20 @@ impl CPU {
         8
       },
       0x69 => {
-        // BIT 5,B
-        let val = self.b;
+        // BIT 5,C
+        let val = self.c;
         self.test_bit_at_r8(val, 5);
         8
       },
       0x6a => {
-        // BIT 5,C
-        let val = self.c;
+        // BIT 5,D
+        let val = self.d;
         self.test_bit_at_r8(val, 5);
         8
       },
       0x6b => {
-        // BIT 5,D
-        let val = self.d;
+        // BIT 5,E
+        let val = self.e;
         self.test_bit_at_r8(val, 5);
         8
       },
@@ -1954,6 +2325,12 @@ impl CPU {
         self.test_bit_at_r8(val, 5);
         8
       },
+      0x6e => {
+        // BIT 5,(HL)
+        let val = self.read_byte(bus, self.hl());
+        self.test_bit_at_r8(val, 5);
+        12
+      },
       0x6f => {
         // BIT 5,A
         let val = self.a;
@@ -1996,6 +2373,12 @@ impl CPU {
         self.test_bit_at_r8(val, 6);
         8
       },
+      0x76 => {
+        // BIT 6,(HL)
+        let val = self.read_byte(bus, self.hl());
+        self.test_bit_at_r8(val, 6);
+        12
+      },
       0x77 => {
         // BIT 6,A
         let val = self.a;
@@ -2038,6 +2421,12 @@ impl CPU {
         self.test_bit_at_r8(val, 7);
         8
       },
+      0x7e => {
+        // BIT 7,(HL)
+        let val = self.read_byte(bus, self.hl());
+        self.test_bit_at_r8(val, 7);
+        12
+      },
       0x7f => {
         // BIT 7,A
         let val = self.a;
@@ -2074,6 +2463,12 @@ impl CPU {
         self.l &= 0b1111_1110;
         8
       },
+      0x86 => {
+        // RES 0,(HL)
+        let value = self.read_byte(bus, self.hl()) & 0b1111_1110;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0x87 => {
         // RES 0,A
         self.a &= 0b1111_1110;
@@ -2109,6 +2504,12 @@ impl CPU {
         self.l &= 0b1111_1101;
         8
       },
+      0x8e => {
+        // RES 1,(HL)
+        let value = self.read_byte(bus, self.hl()) & 0b1111_1101;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0x8f => {
         // RES 1,A
         self.a &= 0b1111_1101;
@@ -2144,6 +2545,12 @@ impl CPU {
         self.l &= 0b1111_1011;
         8
       },
+      0x96 => {
+        // RES 2,(HL)
+        let value = self.read_byte(bus, self.hl()) & 0b1111_1011;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0x97 => {
         // RES 2,A
         self.a &= 0b1111_1011;
@@ -2179,6 +2586,12 @@ impl CPU {
         self.l &= 0b1111_0111;
         8
       },
+      0x9e => {
+        // RES 3,(HL)
+        let value = self.read_byte(bus, self.hl()) & 0b1111_0111;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0x9f => {
         // RES 3,A
         self.a &= 0b1111_0111;
@@ -2214,6 +2627,12 @@ impl CPU {
         self.l &= 0b1110_1111;
         8
       },
+      0xa6 => {
+        // RES 4,(HL)
+        let value = self.read_byte(bus, self.hl()) & 0b1110_1111;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xa7 => {
         // RES 4,A
         self.a &= 0b1110_1111;
@@ -2226,7 +2645,7 @@ impl CPU {
       },
       0xa9 => {
         // RES 5,C
-        self.b &= 0b1101_1111;
+        self.c &= 0b1101_1111;
         8
       },
       0xaa => {
@@ -2249,6 +2668,12 @@ impl CPU {
         self.l &= 0b1101_1111;
         8
       },
+      0xae => {
+        // RES 5,(HL)
+        let value = self.read_byte(bus, self.hl()) & 0b1101_1111;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xaf => {
         // RES 5,A
         self.a &= 0b1101_1111;
@@ -2284,6 +2709,12 @@ impl CPU {
         self.l &= 0b1011_1111;
         8
       },
+      0xb6 => {
+        // RES 6,(HL)
+        let value = self.read_byte(bus, self.hl()) & 0b1011_1111;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xb7 => {
         // RES 6,A
         self.a &= 0b1011_1111;
@@ -2319,6 +2750,12 @@ impl CPU {
         self.l &= 0b0111_1111;
         8
       },
+      0xbe => {
+        // RES 7,(HL)
+        let value = self.read_byte(bus, self.hl()) & 0b0111_1111;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xbf => {
         // RES 7,A
         self.a &= 0b0111_1111;
@@ -2354,6 +2791,12 @@ impl CPU {
         self.l |= 0b0000_0001;
         8
       },
+      0xc6 => {
+        // SET 0,(HL)
+        let value = self.read_byte(bus, self.hl()) | 0b0000_0001;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xc7 => {
         // SET 0,A
         self.a |= 0b0000_0001;
@@ -2389,6 +2832,12 @@ impl CPU {
         self.l |= 0b0000_0010;
         8
       },
+      0xce => {
+        // SET 1,(HL)
+        let value = self.read_byte(bus, self.hl()) | 0b0000_0010;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xcf => {
         // SET 1,A
         self.a |= 0b0000_0010;
@@ -2424,6 +2873,12 @@ impl CPU {
         self.l |= 0b0000_0100;
         8
       },
+      0xd6 => {
+        // SET 2,(HL)
+        let value = self.read_byte(bus, self.hl()) | 0b0000_0100;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xd7 => {
         // SET 2,A
         self.a |= 0b0000_0100;
@@ -2459,6 +2914,12 @@ impl CPU {
         self.l |= 0b0000_1000;
         8
       },
+      0xde => {
+        // SET 3,(HL)
+        let value = self.read_byte(bus, self.hl()) | 0b0000_1000;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xdf => {
         // SET 3,A
         self.a |= 0b0000_1000;
@@ -2494,6 +2955,12 @@ impl CPU {
         self.l |= 0b0001_0000;
         8
       },
+      0xe6 => {
+        // SET 4,(HL)
+        let value = self.read_byte(bus, self.hl()) | 0b0001_0000;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xe7 => {
         // SET 4,A
         self.a |= 0b0001_0000;
@@ -2529,6 +2996,12 @@ impl CPU {
         self.l |= 0b0010_0000;
         8
       },
+      0xee => {
+        // SET 5,(HL)
+        let value = self.read_byte(bus, self.hl()) | 0b0010_0000;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xef => {
         // SET 5,A
         self.a |= 0b0010_0000;
@@ -2564,6 +3037,12 @@ impl CPU {
         self.l |= 0b0100_0000;
         8
       },
+      0xf6 => {
+        // SET 6,(HL)
+        let value = self.read_byte(bus, self.hl()) | 0b0100_0000;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xf7 => {
         // SET 6,A
         self.a |= 0b0100_0000;
@@ -2599,63 +3078,91 @@ impl CPU {
         self.l |= 0b1000_0000;
         8
       },
+      0xfe => {
+        // SET 7,(HL)
+        let value = self.read_byte(bus, self.hl()) | 0b1000_0000;
+        self.write_byte(bus, self.hl(), value);
+        16
+      },
       0xff => {
         // SET 7,A
         self.a |= 0b1000_0000;
         8
       },
-      _ => {
-        unimplemented!()
-      }
     }
   }
 
   fn relative_jump(&mut self, rel_target: i8) {
-    if rel_target < 0 {
-      self.program_counter -= -rel_target as u16;
-    } else {
-      self.program_counter += rel_target as u16;
-    }
+    self.program_counter = self.program_counter.wrapping_add(rel_target as i16 as u16);
   }
 
-  fn push_short(&mut self, memory: &mut Memory, value: u16) {
-    println!("pushing {:x} onto stack", value);
-    self.push_byte(memory, value.hi());
-    self.push_byte(memory, value.lo());
+  // Pushing always waits a cycle first, while SP is decremented
+  fn push_short(&mut self, bus: &mut Bus, value: u16) {
+    trace!("pushing {:x} onto stack", value);
+    self.idle(bus);
+    self.push_byte(bus, value.hi());
+    self.push_byte(bus, value.lo());
   }
 
-  fn push_byte(&mut self, memory: &mut Memory, value: u8) {
+  fn push_byte(&mut self, bus: &mut Bus, value: u8) {
     self.stack_pointer = self.stack_pointer.wrapping_sub(1);
-    memory.write_byte(self.stack_pointer, value);
+    self.write_byte(bus, self.stack_pointer, value);
   }
 
-  fn pop_short(&mut self, memory: &Memory) -> u16 {
-    let lo = self.pop_byte(memory) as u16;
-    let t = (self.pop_byte(memory) as u16) << 8 | lo;
-    println!("popping {:x} off stack", t);
+  fn pop_short(&mut self, bus: &mut Bus) -> u16 {
+    let lo = self.pop_byte(bus) as u16;
+    let t = (self.pop_byte(bus) as u16) << 8 | lo;
+    trace!("popping {:x} off stack", t);
     t
   }
 
-  fn pop_byte(&mut self, memory: &Memory) -> u8 {
-    let x = memory.read_byte(self.stack_pointer);
+  fn pop_byte(&mut self, bus: &mut Bus) -> u8 {
+    let x = self.read_byte(bus, self.stack_pointer);
     self.stack_pointer = self.stack_pointer.wrapping_add(1);
     x
   }
 
-  fn read_short_immediate(&mut self, memory: &Memory) -> u16 {
-    let value = memory.read_short(self.program_counter);
+  // Every memory access takes an M-cycle, and the rest of the hardware keeps going while it happens
+  fn read_byte(&mut self, bus: &mut Bus, address: u16) -> u8 {
+    self.idle(bus);
+    bus.memory.read_byte(address)
+  }
+
+  fn write_byte(&mut self, bus: &mut Bus, address: u16, value: u8) {
+    self.idle(bus);
+    bus.memory.write_byte(address, value);
+  }
+
+  fn read_short(&mut self, bus: &mut Bus, address: u16) -> u16 {
+    let lo = self.read_byte(bus, address) as u16;
+    (self.read_byte(bus, address.wrapping_add(1)) as u16) << 8 | lo
+  }
+
+  fn write_short(&mut self, bus: &mut Bus, address: u16, value: u16) {
+    self.write_byte(bus, address, value.lo());
+    self.write_byte(bus, address.wrapping_add(1), value.hi());
+  }
+
+  // An M-cycle the memory bus isn't used for
+  fn idle(&mut self, bus: &mut Bus) {
+    bus.tick(4);
+    self.cycles_ticked += 4;
+  }
+
+  fn read_short_immediate(&mut self, bus: &mut Bus) -> u16 {
+    let value = self.read_short(bus, self.program_counter);
     self.program_counter += 2;
     value
   }
 
-  fn read_byte_immediate(&mut self, memory: &Memory) -> u8 {
-    let value = memory.read_byte(self.program_counter);
+  fn read_byte_immediate(&mut self, bus: &mut Bus) -> u8 {
+    let value = self.read_byte(bus, self.program_counter);
     self.program_counter += 1;
     value
   }
 
-  fn read_signed_byte_immediate(&mut self, memory: &Memory) -> i8 {
-    let value = memory.read_signed_byte(self.program_counter);
+  fn read_signed_byte_immediate(&mut self, bus: &mut Bus) -> i8 {
+    let value = self.read_byte(bus, self.program_counter) as i8;
     self.program_counter += 1;
     value
   }
@@ -2731,11 +3238,21 @@ impl CPU {
 
   fn cp_r8(&mut self, value: u8) {
     // CP value and register A
-    let result = self.a - value;
+    let result = self.a.wrapping_sub(value);
     self.f.set(ZERO, result == 0);
     self.f.insert(SUBTRACT);
-    self.f.set(HALF_CARRY, result & 0x0f > self.a & 0x0f);
-    self.f.set(CARRY, result < self.a);
+    self.f.set(HALF_CARRY, self.a & 0x0f < value & 0x0f);
+    self.f.set(CARRY, value > self.a);
+  }
+
+  // ADD SP,r8 and LD HL,SP+r8 take their flags from adding the offset to the low byte of SP
+  fn sp_plus_offset(&mut self, offset: i8) -> u16 {
+    let sp = self.stack_pointer;
+    let value = offset as u8 as u16;
+    self.f = Flags::empty();
+    self.f.set(HALF_CARRY, (sp & 0x0f) + (value & 0x0f) > 0x0f);
+    self.f.set(CARRY, (sp & 0xff) + (value & 0xff) > 0xff);
+    sp.wrapping_add(offset as i16 as u16)
   }
 
   fn add_hl_r16(&mut self, register: u16) {
@@ -2765,9 +3282,9 @@ impl CPU {
     (self.d as u16) << 8 | self.e as u16
   }
 
-  fn rst(&mut self, value: u16, memory: &mut Memory) {
+  fn rst(&mut self, value: u16, bus: &mut Bus) {
     let pc = self.program_counter;
-    self.push_short(memory, pc);
+    self.push_short(bus, pc);
     self.program_counter = value;
   }
 
@@ -2779,7 +3296,15 @@ impl CPU {
   }
 }
 
-fn rr_r8(register: &mut u8, f: &mut Flags) {
+fn rlc_r8(register: &mut u8, f: &mut Flags) {
+  *register = register.rotate_left(1);
+  f.set(ZERO, *register == 0);
+  f.remove(SUBTRACT);
+  f.remove(HALF_CARRY);
+  f.set(CARRY, *register & 0b0000_0001 == 0b0000_0001);
+}
+
+fn rrc_r8(register: &mut u8, f: &mut Flags) {
   *register = register.rotate_right(1);
   f.set(ZERO, *register == 0);
   f.remove(SUBTRACT);
@@ -2787,17 +3312,58 @@ fn rr_r8(register: &mut u8, f: &mut Flags) {
   f.set(CARRY, *register & 0b1000_0000 == 0b1000_0000);
 }
 
+fn rr_r8(register: &mut u8, f: &mut Flags) {
+  // Rotate through the carry flag
+  let carry_in = if f.contains(CARRY) { 0b1000_0000 } else { 0 };
+  let carry_out = *register & 0b0000_0001 == 0b0000_0001;
+  *register = (*register >> 1) | carry_in;
+  f.set(ZERO, *register == 0);
+  f.remove(SUBTRACT);
+  f.remove(HALF_CARRY);
+  f.set(CARRY, carry_out);
+}
+
 fn rl_r8(register: &mut u8, f: &mut Flags) {
-  *register = register.rotate_left(1);
+  // Rotate through the carry flag
+  let carry_in = if f.contains(CARRY) { 0b0000_0001 } else { 0 };
+  let carry_out = *register & 0b1000_0000 == 0b1000_0000;
+  *register = (*register << 1) | carry_in;
   f.set(ZERO, *register == 0);
   f.remove(SUBTRACT);
   f.remove(HALF_CARRY);
-  f.set(CARRY, *register & 0b0000_0001 == 0b0000_0001);
+  f.set(CARRY, carry_out);
+}
+
+fn sla_r8(register: &mut u8, f: &mut Flags) {
+  let carry_out = *register & 0b1000_0000 == 0b1000_0000;
+  *register <<= 1;
+  f.set(ZERO, *register == 0);
+  f.remove(SUBTRACT);
+  f.remove(HALF_CARRY);
+  f.set(CARRY, carry_out);
+}
+
+fn sra_r8(register: &mut u8, f: &mut Flags) {
+  // Bit 7 stays the same
+  let carry_out = *register & 0b0000_0001 == 0b0000_0001;
+  *register = (*register >> 1) | (*register & 0b1000_0000);
+  f.set(ZERO, *register == 0);
+  f.remove(SUBTRACT);
+  f.remove(HALF_CARRY);
+  f.set(CARRY, carry_out);
+}
+
+fn srl_r8(register: &mut u8, f: &mut Flags) {
+  let carry_out = *register & 0b0000_0001 == 0b0000_0001;
+  *register >>= 1;
+  f.set(ZERO, *register == 0);
+  f.remove(SUBTRACT);
+  f.remove(HALF_CARRY);
+  f.set(CARRY, carry_out);
 }
 
 fn swap_r8(register: &mut u8, f: &mut Flags) {
-  let upper = *register & 0xf0;
-  *register = (*register << 4) | upper;
+  *register = register.rotate_left(4);
   f.set(ZERO, *register == 0);
   f.remove(SUBTRACT);
   f.remove(HALF_CARRY);
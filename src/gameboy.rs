@@ -0,0 +1,114 @@
+use apu::SampleSink;
+use boot;
+use bus::Bus;
+use cpu::CPU;
+use image::{ImageBuffer, Rgba};
+use joypad::Button;
+use memory::Memory;
+use palette::Palette;
+use ppu;
+use rom::{self, CgbSupport, RomError};
+use serial::Link;
+use sgb::Sgb;
+use std::io;
+use std::path::PathBuf;
+
+// The master clock, in cycles a second. Double speed mode runs the CPU at twice this
+pub const CLOCK_SPEED: i64 = 4194304;
+
+// A whole Game Boy, for frontends and tools to drive
+pub struct GameBoy {
+  bus: Bus,
+  cpu: CPU,
+  // Cycles we've been asked to run but haven't got to yet, since instructions don't split
+  cycles: i64
+}
+
+impl GameBoy {
+  // Runs the boot ROM if there is one, otherwise starts the game in the state it would have left things.
+  // Colour games run on a CGB unless force_dmg is set, and the rest on a Super Game Boy if sgb is set
+  // Battery backed RAM is kept in save_path, if the cartridge has any
+  pub fn new(rom_path: &str, save_path: PathBuf, boot_rom: Option<Vec<u8>>, force_dmg: bool, sgb: bool) -> Result<GameBoy, RomError> {
+    let mut memory = Memory::new();
+    let header = rom::load_rom(&mut memory, rom_path, save_path)?;
+    memory.cgb = header.cgb != CgbSupport::None && !force_dmg;
+    if sgb && !memory.cgb {
+      if !header.sgb {
+        warn!("Game doesn't support the Super Game Boy, it will only get the default palette");
+      }
+      memory.sgb = Some(Sgb::new());
+    }
+    let cpu = match boot_rom {
+      Some(boot_rom) => {
+        memory.map_boot_rom(boot_rom);
+        CPU::new()
+      },
+      None => {
+        boot::skip_boot_rom(&mut memory);
+        CPU::post_boot(memory.cgb)
+      }
+    };
+    Ok(GameBoy {
+      bus: Bus::new(memory),
+      cpu: cpu,
+      cycles: 0
+    })
+  }
+
+  // Returns how long the instruction took. Time is measured in normal speed cycles,
+  // however fast the CPU is going
+  pub fn step_instruction(&mut self) -> i64 {
+    let cycles = self.cpu.step(&mut self.bus);
+    self.bus.memory.normal_speed_cycles(cycles)
+  }
+
+  pub fn run_cycles(&mut self, cycles: i64) {
+    self.cycles += cycles;
+    while self.cycles > 0 {
+      self.cycles -= self.step_instruction();
+    }
+  }
+
+  // Runs until the PPU finishes a frame, or for as long as a frame would take while the LCD is off
+  pub fn run_frame(&mut self) {
+    self.bus.ppu.take_frame_done();
+    let mut cycles = 0;
+    while !self.bus.ppu.take_frame_done() && cycles < ppu::FRAME_CYCLES {
+      cycles += self.step_instruction();
+    }
+  }
+
+  // The last frame the PPU finished, without any SGB border
+  pub fn frame_buffer(&self) -> &ImageBuffer<Rgba<u8>, Vec<u8>> {
+    self.bus.ppu.frame_buffer()
+  }
+
+  // The screen as it should be shown, SGB border and all
+  pub fn draw(&self) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    self.bus.ppu.draw(&self.bus.memory)
+  }
+
+  pub fn set_button(&mut self, button: Button, pressed: bool) {
+    self.bus.memory.set_button(button, pressed);
+  }
+
+  pub fn set_palette(&mut self, palette: Palette) {
+    self.bus.ppu.set_palette(palette);
+  }
+
+  pub fn set_link(&mut self, link: Box<Link>) {
+    self.bus.memory.serial.set_link(link);
+  }
+
+  pub fn set_audio_output(&mut self, output: Box<SampleSink>) {
+    self.bus.memory.apu.set_output(output);
+  }
+
+  pub fn finish_audio_output(&mut self) -> io::Result<()> {
+    self.bus.memory.apu.finish_output()
+  }
+
+  pub fn flush_save(&mut self) -> io::Result<()> {
+    self.bus.memory.cartridge.flush_save()
+  }
+}
@@ -0,0 +1,85 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+  Right,
+  Left,
+  Up,
+  Down,
+  A,
+  B,
+  Select,
+  Start
+}
+
+const SELECT_DIRECTIONS: u8 = 0b0001_0000;
+const SELECT_ACTIONS: u8 = 0b0010_0000;
+
+// 0xFF00 (P1)
+pub struct Joypad {
+  // Bits 4 and 5 as last written, a 0 selects that group of buttons
+  select: u8,
+  // A 1 means pressed, bits are in the same order as the P1 lines
+  // Right/A, Left/B, Up/Select, Down/Start
+  directions: u8,
+  actions: u8
+}
+
+impl Joypad {
+  pub fn new() -> Joypad {
+    Joypad {
+      select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+      directions: 0,
+      actions: 0
+    }
+  }
+
+  pub fn read(&self) -> u8 {
+    // Lines are pulled low when pressed, unused bits read as 1
+    0b1100_0000 | self.select | (!self.lines() & 0x0f)
+  }
+
+  // Returns true if the joypad interrupt should be requested
+  pub fn write(&mut self, value: u8) -> bool {
+    let old_lines = self.lines();
+    self.select = value & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+    self.lines() & !old_lines != 0
+  }
+
+  // Returns true if the joypad interrupt should be requested
+  pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+    let old_lines = self.lines();
+    let (group, bit) = match button {
+      Button::Right => (&mut self.directions, 0b0001),
+      Button::Left => (&mut self.directions, 0b0010),
+      Button::Up => (&mut self.directions, 0b0100),
+      Button::Down => (&mut self.directions, 0b1000),
+      Button::A => (&mut self.actions, 0b0001),
+      Button::B => (&mut self.actions, 0b0010),
+      Button::Select => (&mut self.actions, 0b0100),
+      Button::Start => (&mut self.actions, 0b1000)
+    };
+    if pressed {
+      *group |= bit;
+    } else {
+      *group &= !bit;
+    }
+    // The interrupt fires when a line goes from high to low
+    self.lines() & !old_lines != 0
+  }
+
+  // Used to wake up from STOP
+  pub fn any_line_low(&self) -> bool {
+    self.lines() != 0
+  }
+
+  // Which of the 4 lines are currently pulled low (1 = low)
+  fn lines(&self) -> u8 {
+    let mut lines = 0;
+    if self.select & SELECT_DIRECTIONS == 0 {
+      lines |= self.directions;
+    }
+    if self.select & SELECT_ACTIONS == 0 {
+      lines |= self.actions;
+    }
+    lines
+  }
+}
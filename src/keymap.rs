@@ -0,0 +1,87 @@
+use glutin::VirtualKeyCode;
+use bamegoy::joypad::Button;
+use std::collections::HashMap;
+
+pub struct KeyMap {
+  bindings: HashMap<VirtualKeyCode, Button>
+}
+
+impl KeyMap {
+  pub fn new() -> KeyMap {
+    let mut key_map = KeyMap { bindings: HashMap::new() };
+    key_map.bind(VirtualKeyCode::Right, Button::Right);
+    key_map.bind(VirtualKeyCode::Left, Button::Left);
+    key_map.bind(VirtualKeyCode::Up, Button::Up);
+    key_map.bind(VirtualKeyCode::Down, Button::Down);
+    key_map.bind(VirtualKeyCode::X, Button::A);
+    key_map.bind(VirtualKeyCode::Z, Button::B);
+    key_map.bind(VirtualKeyCode::Back, Button::Select);
+    key_map.bind(VirtualKeyCode::Return, Button::Start);
+    key_map
+  }
+
+  // Overrides the defaults with `key_<button> = <key>` entries from the config
+  pub fn from_config(config: &[(String, String)]) -> KeyMap {
+    let mut key_map = KeyMap::new();
+    for &(ref name, ref value) in config {
+      if !name.starts_with("key_") {
+        continue;
+      }
+      let button = match &name[4..] {
+        "right" => Button::Right,
+        "left" => Button::Left,
+        "up" => Button::Up,
+        "down" => Button::Down,
+        "a" => Button::A,
+        "b" => Button::B,
+        "select" => Button::Select,
+        "start" => Button::Start,
+        _ => {
+          println!("Unknown button {} in config", name);
+          continue;
+        }
+      };
+      match key_from_name(value) {
+        Some(key) => key_map.bind(key, button),
+        None => println!("Unknown key {} in config", value)
+      }
+    }
+    key_map
+  }
+
+  // Each button has exactly one key, so this replaces any existing key for the button
+  pub fn bind(&mut self, key: VirtualKeyCode, button: Button) {
+    self.bindings.retain(|_, v| *v != button);
+    self.bindings.insert(key, button);
+  }
+
+  pub fn get(&self, key: VirtualKeyCode) -> Option<Button> {
+    self.bindings.get(&key).cloned()
+  }
+}
+
+fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+  use glutin::VirtualKeyCode::*;
+  let key = match &*name.to_lowercase() {
+    "a" => A, "b" => B, "c" => C, "d" => D, "e" => E, "f" => F, "g" => G,
+    "h" => H, "i" => I, "j" => J, "k" => K, "l" => L, "m" => M, "n" => N,
+    "o" => O, "p" => P, "q" => Q, "r" => R, "s" => S, "t" => T, "u" => U,
+    "v" => V, "w" => W, "x" => X, "y" => Y, "z" => Z,
+    "0" => Key0, "1" => Key1, "2" => Key2, "3" => Key3, "4" => Key4,
+    "5" => Key5, "6" => Key6, "7" => Key7, "8" => Key8, "9" => Key9,
+    "right" => Right,
+    "left" => Left,
+    "up" => Up,
+    "down" => Down,
+    "return" | "enter" => Return,
+    "back" | "backspace" => Back,
+    "space" => Space,
+    "tab" => Tab,
+    "lshift" => LShift,
+    "rshift" => RShift,
+    "lcontrol" => LControl,
+    "rcontrol" => RControl,
+    _ => return None
+  };
+  Some(key)
+}
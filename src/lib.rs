@@ -0,0 +1,28 @@
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate bitflags;
+extern crate image;
+#[macro_use]
+extern crate enum_primitive;
+
+pub mod gameboy;
+pub mod cpu;
+pub mod memory;
+pub mod bus;
+pub mod rom;
+pub mod boot;
+pub mod cgb;
+pub mod sgb;
+pub mod cartridge;
+pub mod rtc;
+pub mod timer;
+pub mod serial;
+pub mod link;
+pub mod apu;
+pub mod wav;
+pub mod joypad;
+pub mod palette;
+pub mod util;
+pub mod ppu;
+pub mod debug;
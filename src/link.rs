@@ -0,0 +1,129 @@
+use serial::Link;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// Both ends swap state and wait for each other every this many cycles, which keeps the two
+// emulators in lockstep. A byte takes 4096 cycles on the internal clock, so there is at most
+// one transfer between syncs
+const SYNC_CYCLES: i64 = 2048;
+
+// How long we'll wait on the other end at a sync before giving up on it. Syncs happen on the
+// thread that draws the window, so a hung peer would freeze us without this
+const TIMEOUT_SECS: u64 = 2;
+
+const WAITING: u8 = 0b01;
+const SENT: u8 = 0b10;
+
+// A link cable to another bamegoy over TCP.
+// Transfers are worked out from the state both ends agreed on at the last sync, rather than
+// whatever the other end happens to be doing in real time, so they play out the same every run
+pub struct TcpLink {
+  stream: Option<TcpStream>,
+  cycles: i64,
+  // What we tell the other end at the next sync
+  waiting: Option<u8>,
+  sent: Option<u8>,
+  // What the other end told us at the last sync
+  their_waiting: Option<u8>,
+  received: Option<u8>
+}
+
+impl TcpLink {
+  // Waits for the other emulator to connect
+  pub fn listen<A: ToSocketAddrs>(address: A) -> io::Result<TcpLink> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    TcpLink::new(stream)
+  }
+
+  pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<TcpLink> {
+    TcpLink::new(TcpStream::connect(address)?)
+  }
+
+  fn new(stream: TcpStream) -> io::Result<TcpLink> {
+    // Syncs are tiny and frequent, so don't let them sit around waiting to be batched up
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+    Ok(TcpLink {
+      stream: Some(stream),
+      cycles: 0,
+      waiting: None,
+      sent: None,
+      their_waiting: None,
+      received: None
+    })
+  }
+
+  fn sync(&mut self) -> io::Result<()> {
+    let mut message = [0; 3];
+    if let Some(value) = self.waiting.take() {
+      message[0] |= WAITING;
+      message[1] = value;
+    }
+    if let Some(value) = self.sent.take() {
+      message[0] |= SENT;
+      message[2] = value;
+    }
+    let mut reply = [0; 3];
+    if let Some(ref mut stream) = self.stream {
+      stream.write_all(&message)?;
+      stream.read_exact(&mut reply)?;
+    }
+    self.their_waiting = if reply[0] & WAITING == WAITING {
+      Some(reply[1])
+    } else {
+      None
+    };
+    // Anything from last time that we didn't pick up is gone, we stopped waiting for it
+    self.received = if reply[0] & SENT == SENT {
+      Some(reply[2])
+    } else {
+      None
+    };
+    Ok(())
+  }
+}
+
+impl Link for TcpLink {
+  fn exchange(&mut self, value: u8) -> u8 {
+    match self.their_waiting.take() {
+      Some(theirs) => {
+        self.sent = Some(value);
+        theirs
+      },
+      None => 0xff
+    }
+  }
+
+  fn poll(&mut self, value: u8) -> Option<u8> {
+    match self.received.take() {
+      Some(received) => {
+        self.waiting = None;
+        Some(received)
+      },
+      None => {
+        self.waiting = Some(value);
+        None
+      }
+    }
+  }
+
+  fn step(&mut self, cycles: i64) {
+    if self.stream.is_none() {
+      return;
+    }
+    self.cycles += cycles;
+    while self.cycles >= SYNC_CYCLES {
+      self.cycles -= SYNC_CYCLES;
+      if let Err(e) = self.sync() {
+        warn!("Link cable disconnected: {}", e);
+        self.stream = None;
+        self.their_waiting = None;
+        self.received = None;
+        return;
+      }
+    }
+  }
+}
@@ -6,24 +6,25 @@ extern crate glium;
 #[macro_use]
 extern crate log;
 extern crate log_panics;
-#[macro_use]
-extern crate bitflags;
 extern crate image;
-#[macro_use]
-extern crate enum_primitive;
+extern crate cpal;
+extern crate futures;
+extern crate bamegoy;
 
 use glium::DisplayBuild;
 use glium::Surface;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 use conrod::{color, widget};
 use conrod::{Colorable, Positionable, Widget, Sizeable};
+use bamegoy::gameboy::{self, GameBoy};
+use bamegoy::{boot, link, palette, serial, wav};
+use image::{ImageBuffer, Rgba};
 
-mod cpu;
-mod memory;
-mod rom;
-mod util;
-mod ppu;
-mod debug;
+mod keymap;
+mod config;
+mod audio;
 
 widget_ids!(
     struct Ids {
@@ -31,7 +32,143 @@ widget_ids!(
     }
 );
 
+struct Options {
+    rom_path: String,
+    // Run this boot ROM before the game, rather than starting the game in the state it leaves things
+    boot_rom_path: Option<String>,
+    // Run colour games as if on a DMG
+    force_dmg: bool,
+    // Run on a Super Game Boy, for games with borders and colour palettes
+    sgb: bool,
+    // Run headless, writing the sound to this WAV file instead of playing it
+    wav_path: Option<String>,
+    // Also write each channel to its own WAV file
+    wav_channels: bool,
+    // How many frames to run for when headless
+    frames: u64,
+    // Print bytes sent over the link cable
+    serial_log: bool,
+    // Run a second game without a window, plugged in to the link cable
+    link_rom_path: Option<String>,
+    // Link to another bamegoy over TCP, either waiting for it to connect or connecting to it
+    link_listen: Option<String>,
+    link_connect: Option<String>,
+    // Log every instruction, which is far too slow to leave on
+    trace: bool
+}
+
+fn parse_args() -> Options {
+    let mut rom_path = None;
+    let mut boot_rom_path = None;
+    let mut force_dmg = false;
+    let mut sgb = false;
+    let mut wav_path = None;
+    let mut wav_channels = false;
+    let mut frames = 600;
+    let mut serial_log = false;
+    let mut link_rom_path = None;
+    let mut link_listen = None;
+    let mut link_connect = None;
+    let mut trace = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--boot-rom" => boot_rom_path = Some(args.next().expect("--boot-rom expects a file path")),
+            "--dmg" => force_dmg = true,
+            "--sgb" => sgb = true,
+            "--wav" => wav_path = Some(args.next().expect("--wav expects a file path")),
+            "--wav-channels" => wav_channels = true,
+            "--frames" => frames = args.next().and_then(|f| f.parse().ok()).expect("--frames expects a number of frames"),
+            "--serial-log" => serial_log = true,
+            "--link-rom" => link_rom_path = Some(args.next().expect("--link-rom expects a file path")),
+            "--link-listen" => link_listen = Some(args.next().expect("--link-listen expects an address, like 127.0.0.1:8765")),
+            "--link-connect" => link_connect = Some(args.next().expect("--link-connect expects an address, like 127.0.0.1:8765")),
+            "--trace" => trace = true,
+            _ => rom_path = Some(arg)
+        }
+    }
+    Options {
+        rom_path: rom_path.expect("Gameboy ROM expected as argument"),
+        boot_rom_path: boot_rom_path,
+        force_dmg: force_dmg,
+        sgb: sgb,
+        wav_path: wav_path,
+        wav_channels: wav_channels,
+        frames: frames,
+        serial_log: serial_log,
+        link_rom_path: link_rom_path,
+        link_listen: link_listen,
+        link_connect: link_connect,
+        trace: trace
+    }
+}
+
+// Logs to stderr, so it doesn't get mixed up with serial output on stdout
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, _: &log::LogMetadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::LogRecord) {
+        let _ = writeln!(std::io::stderr(), "{} - {}", record.level(), record.args());
+    }
+}
+
+fn init_logging(trace: bool) {
+    // Anything below the maximum level isn't even formatted, so leaving tracing off costs nothing
+    let level = if trace { log::LogLevelFilter::Trace } else { log::LogLevelFilter::Info };
+    let _ = log::set_logger(|max_log_level| {
+        max_log_level.set(level);
+        Box::new(StderrLogger)
+    });
+}
+
+fn load_game(rom_path: &str, save_path: PathBuf, options: &Options) -> GameBoy {
+    let boot_rom = options.boot_rom_path.as_ref().map(|path| boot::load_boot_rom(path).expect("Failed to load boot ROM"));
+    GameBoy::new(rom_path, save_path, boot_rom, options.force_dmg, options.sgb).unwrap()
+}
+
+// Runs as fast as possible without a window or audio device, writing the sound out to WAV files
+fn capture_audio(options: &Options, wav_path: &str) {
+    let mut game = load_game(&options.rom_path, Path::new(&options.rom_path).with_extension("sav"), options);
+    if options.serial_log {
+        game.set_link(Box::new(serial::StdoutLogger));
+    }
+    let capture = wav::WavCapture::create(Path::new(wav_path), options.wav_channels).expect("Failed to create WAV file");
+    game.set_audio_output(Box::new(capture));
+
+    for _ in 0..options.frames {
+        game.run_frame();
+    }
+    if let Err(e) = game.finish_audio_output() {
+        println!("Failed to write WAV file: {}", e);
+    }
+    if let Err(e) = game.flush_save() {
+        println!("Failed to write save file: {}", e);
+    }
+}
+
+// How many cycles the real thing would have run in this long
+fn elapsed_cycles(elapsed: Duration) -> i64 {
+    let nanos = elapsed.as_secs() as i64 * 1000000000 + elapsed.subsec_nanos() as i64;
+    nanos * gameboy::CLOCK_SPEED / 1000000000
+}
+
+fn to_texture(image: ImageBuffer<Rgba<u8>, Vec<u8>>) -> glium::texture::RawImage2d<'static, u8> {
+    let dimensions = image.dimensions();
+    glium::texture::RawImage2d::from_raw_rgba_reversed(image.into_raw(), dimensions)
+}
+
 fn main() {
+    let options = parse_args();
+    init_logging(options.trace);
+    if let Some(ref wav_path) = options.wav_path {
+        capture_audio(&options, wav_path);
+        return;
+    }
+
     let display = glium::glutin::WindowBuilder::new()
     .with_title(option_env!("CARGO_PKG_NAME").unwrap_or("unknown"))
     .with_dimensions(800, 600)
@@ -46,21 +183,42 @@ fn main() {
 
     let mut image_map = conrod::image::Map::<glium::texture::Texture2d>::new();
 
-    let mut memory = memory::Memory::new();
-    let mut cpu = cpu::CPU::new();
-    let mut ppu = ppu::PPU::new();
-    memory.memory[0xff44] = 0; // Start at scanline 0
-    memory.memory[0xff40] &= 0x80; // Flag LCD as on
-    let rom_path = std::env::args().nth(1).expect("Gameboy ROM expected as argument");
+    let mut game = load_game(&options.rom_path, Path::new(&options.rom_path).with_extension("sav"), &options);
+    if options.serial_log {
+        game.set_link(Box::new(serial::StdoutLogger));
+    }
+    // The second game is kept in step with ours, so transfers between them are deterministic
+    let mut link_game = options.link_rom_path.as_ref().map(|path| {
+        // Its own save file, so the two games don't overwrite each other's when they're the same ROM
+        let mut link_game = load_game(path, Path::new(path).with_extension("link.sav"), &options);
+        let (ours, theirs) = serial::Cable::new();
+        game.set_link(Box::new(ours));
+        link_game.set_link(Box::new(theirs));
+        link_game
+    });
+    if let Some(ref address) = options.link_listen {
+        println!("Waiting for the other end of the link cable to connect on {}", address);
+        let link = link::TcpLink::listen(address.as_str()).expect("Failed to set up link cable");
+        game.set_link(Box::new(link));
+    } else if let Some(ref address) = options.link_connect {
+        let link = link::TcpLink::connect(address.as_str()).expect("Failed to connect link cable");
+        game.set_link(Box::new(link));
+    }
 
-    rom::load_rom(&mut memory, &rom_path).unwrap();
+    let config = config::load(config::CONFIG_PATH).unwrap_or_else(|_| Vec::new());
+    let key_map = keymap::KeyMap::from_config(&config);
+    game.set_palette(palette::Palette::from_config(&config));
+    match audio::AudioOutput::new() {
+        Some(output) => game.set_audio_output(Box::new(output)),
+        None => println!("No audio device found, running without sound")
+    }
 
     let mut last_time = Instant::now();
-    let mut cpu_acc = 0;
-    let mut ppu_acc = 0;
+    let mut last_save = Instant::now();
+    // Cycles owed to the linked games, which are run an instruction at a time
+    let mut linked_cycles = 0;
     let game_screen = {
-        let draw_results = ppu.draw(&memory);
-        let texture = glium::texture::Texture2d::new(&display, draw_results.0).unwrap();
+        let texture = glium::texture::Texture2d::new(&display, to_texture(game.draw())).unwrap();
         image_map.insert(texture)
     };
     'game: loop {
@@ -68,8 +226,6 @@ fn main() {
         if elapsed > Duration::from_millis(100) {
             elapsed = Duration::from_millis(100);
         };
-        cpu_acc += (elapsed.as_secs() as i64 * 1000000000) + elapsed.subsec_nanos() as i64;
-        ppu_acc += (elapsed.as_secs() as i64 * 1000000000) + elapsed.subsec_nanos() as i64;
         last_time = Instant::now();
 
         for event in display.poll_events() {
@@ -80,6 +236,11 @@ fn main() {
 
             match event {
                 glutin::Event::Closed => break 'game,
+                glutin::Event::KeyboardInput(state, _, Some(key)) => {
+                    if let Some(button) = key_map.get(key) {
+                        game.set_button(button, state == glutin::ElementState::Pressed);
+                    }
+                }
                 glutin::Event::Resized(width, height) => {
                     // Doo dad
                 }
@@ -88,27 +249,29 @@ fn main() {
         }
 
 
-        // TODO:
-        // I think it would be cool to emulate the next step and see how long that took
-        // then only follow through on it if we banked enough time
-        // right now we are going too fast
-        let mut did_something = true;
-        while did_something {
-            did_something = false;
-            if cpu_acc > 952 {
-                cpu_acc -= cpu.step(&mut memory) * 238;
-                did_something = true;
-            }
-            if ppu_acc > ppu.estimate_clock_cycles() as i64 * 238 {
-                ppu_acc -= ppu.step(&mut memory) * 238;
-                did_something = true;
-            }
+        // Catch up on however much time has passed since last time round
+        match link_game {
+            Some(ref mut link_game) => {
+                linked_cycles += elapsed_cycles(elapsed);
+                while linked_cycles > 0 {
+                    let cycles = game.step_instruction();
+                    link_game.run_cycles(cycles);
+                    linked_cycles -= cycles;
+                }
+            },
+            None => game.run_cycles(elapsed_cycles(elapsed))
         }
-        while ppu_acc > ppu.estimate_clock_cycles() * 238 {
-            
+        // Flush battery backed RAM every so often so that a crash doesn't lose progress
+        if last_save.elapsed() > Duration::from_secs(1) {
+            if let Err(e) = game.flush_save() {
+                println!("Failed to write save file: {}", e);
+            }
+            last_save = Instant::now();
         }
-        let draw_results = ppu.draw(&memory);
-        let texture = glium::texture::Texture2d::new(&display, draw_results.0).unwrap();
+
+        let screen = game.draw();
+        let screen_size = screen.dimensions();
+        let texture = glium::texture::Texture2d::new(&display, to_texture(screen)).unwrap();
         let _ = image_map.replace(game_screen, texture);
         ui.needs_redraw();
 
@@ -122,7 +285,8 @@ fn main() {
             .label_color(color::WHITE)
             .set(ids.tabs, ui);
 
-            widget::Image::new(game_screen).w_h(256.0f64, 256.0f64).middle_of(ids.tab_game).set(ids.game_screen, ui);
+            let (width, height) = screen_size;
+            widget::Image::new(game_screen).w_h(width as f64 * 2.0, height as f64 * 2.0).middle_of(ids.tab_game).set(ids.game_screen, ui);
         }
 
         // Render the `Ui` and then display it on the screen.
@@ -134,4 +298,12 @@ fn main() {
             target.finish().unwrap();
         }
     }
+    if let Err(e) = game.flush_save() {
+        println!("Failed to write save file: {}", e);
+    }
+    if let Some(ref mut link_game) = link_game {
+        if let Err(e) = link_game.flush_save() {
+            println!("Failed to write save file: {}", e);
+        }
+    }
 }
@@ -1,5 +1,12 @@
 use std;
-use util::LoHi;
+use cartridge::Cartridge;
+use cpu::{InterruptFlags, JOYPAD, SERIAL, TIMER};
+use joypad::{Button, Joypad};
+use timer::Timer;
+use apu::Apu;
+use serial::Serial;
+use cgb::{Hdma, PaletteRam};
+use sgb::Sgb;
 
 /* 
 Helpful reference!
@@ -26,50 +33,390 @@ struct Memory {
 }
 */
 
+// OAM DMA copies 160 bytes into OAM, one byte per M-cycle
+const DMA_LENGTH: u16 = 160;
+
+struct Dma {
+  source: u16,
+  copied: u16,
+  // Cycles not yet spent copying
+  cycles: i64
+}
+
 pub struct Memory {
-  pub memory: Box<[u8; 65536]>
+  pub memory: Box<[u8; 65536]>,
+  pub cartridge: Cartridge,
+  pub timer: Timer,
+  pub joypad: Joypad,
+  pub serial: Serial,
+  pub apu: Apu,
+  dma: Option<Dma>,
+  // Mapped over the start of the cartridge until the boot ROM unmaps itself
+  boot_rom: Option<Vec<u8>>,
+  // Game Boy Color hardware, which is only there when running a CGB game
+  pub cgb: bool,
+  pub double_speed: bool,
+  // KEY1 bit 0, the next STOP switches speed
+  speed_switch_armed: bool,
+  vram_bank: usize,
+  // VRAM bank 0 lives in memory
+  vram_bank_one: Box<[u8; 0x2000]>,
+  wram_bank: usize,
+  // WRAM banks 0 and 1 live in memory, these are banks 2 - 7
+  wram_banks: Box<[u8; 0x6000]>,
+  pub bg_palettes: PaletteRam,
+  pub obj_palettes: PaletteRam,
+  hdma: Hdma,
+  // Cycles the CPU has to sit out while HDMA copies
+  stall_cycles: i64,
+  // Super Game Boy, listening in on P1
+  pub sgb: Option<Sgb>
 }
 
 impl Memory {
   pub fn new() -> Memory {
     Memory {
-      memory: Box::new(unsafe { std::mem::zeroed() })
+      memory: Box::new(unsafe { std::mem::zeroed() }),
+      cartridge: Cartridge::empty(),
+      timer: Timer::new(),
+      joypad: Joypad::new(),
+      serial: Serial::new(),
+      apu: Apu::new(),
+      dma: None,
+      boot_rom: None,
+      cgb: false,
+      double_speed: false,
+      speed_switch_armed: false,
+      vram_bank: 0,
+      vram_bank_one: Box::new([0; 0x2000]),
+      wram_bank: 1,
+      wram_banks: Box::new([0; 0x6000]),
+      bg_palettes: PaletteRam::new(),
+      obj_palettes: PaletteRam::new(),
+      hdma: Hdma::new(),
+      stall_cycles: 0,
+      sgb: None
+    }
+  }
+
+  pub fn map_boot_rom(&mut self, boot_rom: Vec<u8>) {
+    self.boot_rom = Some(boot_rom);
+  }
+
+  // Advances the hardware that lives on the memory bus
+  pub fn tick(&mut self, cycles: i64) {
+    if self.timer.step(cycles) {
+      self.request_interrupt(TIMER);
+    }
+    if self.serial.step(cycles) {
+      self.request_interrupt(SERIAL);
+    }
+    self.step_dma(cycles);
+    // The APU runs at the same speed whatever the CPU is doing
+    let cycles = self.normal_speed_cycles(cycles);
+    self.apu.step(cycles);
+  }
+
+  // Converts CPU cycles to cycles of the (normal speed) clock the PPU and APU run off
+  pub fn normal_speed_cycles(&self, cycles: i64) -> i64 {
+    if self.double_speed {
+      cycles / 2
+    } else {
+      cycles
+    }
+  }
+
+  // Called on STOP, returns true if it was a speed switch rather than a real stop
+  pub fn switch_speed(&mut self) -> bool {
+    if !self.speed_switch_armed {
+      return false;
+    }
+    self.speed_switch_armed = false;
+    self.double_speed = !self.double_speed;
+    true
+  }
+
+  pub fn take_stall_cycles(&mut self) -> i64 {
+    let cycles = self.stall_cycles;
+    self.stall_cycles = 0;
+    cycles
+  }
+
+  // Called by the PPU at the start of every HBlank
+  pub fn hblank(&mut self) {
+    if self.hdma.active {
+      self.copy_hdma_block();
+      if self.hdma.blocks_left == 0 {
+        self.hdma.active = false;
+      }
+    }
+  }
+
+  fn copy_hdma_block(&mut self) {
+    for i in 0..16 {
+      let value = self.read_bus(self.hdma.source.wrapping_add(i));
+      let destination = 0x8000 | ((self.hdma.destination + i) & 0x1fff);
+      self.write_vram(destination, value);
+    }
+    self.hdma.source = self.hdma.source.wrapping_add(16);
+    self.hdma.destination = (self.hdma.destination + 16) & 0x1fff;
+    self.hdma.blocks_left -= 1;
+    // 8 M-cycles a block at normal speed, which is twice as many at double speed
+    self.stall_cycles += if self.double_speed { 64 } else { 32 };
+  }
+
+  fn write_hdma_control(&mut self, value: u8) {
+    if self.hdma.active {
+      // Writing with bit 7 clear stops an HBlank DMA
+      if value & 0x80 == 0 {
+        self.hdma.active = false;
+        return;
+      }
+    }
+    self.hdma.blocks_left = (value & 0x7f) + 1;
+    if value & 0x80 == 0x80 {
+      self.hdma.active = true;
+    } else {
+      // General purpose DMA does the whole thing in one go
+      while self.hdma.blocks_left > 0 {
+        self.copy_hdma_block();
+      }
     }
   }
 
+  // VRAM in a particular bank, for the PPU
+  pub fn vram(&self, bank: usize, address: usize) -> u8 {
+    if bank == 1 {
+      self.vram_bank_one[address - 0x8000]
+    } else {
+      self.memory[address]
+    }
+  }
+
+  fn write_vram(&mut self, address: u16, value: u8) {
+    if self.vram_bank == 1 {
+      self.vram_bank_one[address as usize - 0x8000] = value;
+    } else {
+      self.memory[address as usize] = value;
+    }
+  }
+
+  // Index into the switchable WRAM banks, when one of them is mapped at address
+  fn wram_bank_address(&self, address: u16) -> Option<usize> {
+    let in_bank = (address >= 0xD000 && address <= 0xDFFF) || (address >= 0xF000 && address <= 0xFDFF);
+    if in_bank && self.wram_bank >= 2 {
+      Some((self.wram_bank - 2) * 0x1000 + (address as usize & 0x0fff))
+    } else {
+      None
+    }
+  }
+
+  // Palette RAM can't be touched during pixel transfer
+  fn palette_locked(&self) -> bool {
+    self.memory[0xff40] & 0x80 != 0 && self.memory[0xff41] & 0b11 == 3
+  }
+
+  fn write_cgb_register(&mut self, address: u16, value: u8) {
+    match address {
+      0xFF4D => self.speed_switch_armed = value & 0x01 == 0x01,
+      0xFF4F => self.vram_bank = (value & 0x01) as usize,
+      0xFF51..=0xFF54 => self.hdma.write_byte(address, value),
+      0xFF55 => self.write_hdma_control(value),
+      0xFF68 => self.bg_palettes.write_spec(value),
+      0xFF69 => {
+        if !self.palette_locked() {
+          self.bg_palettes.write_data(value);
+        }
+      },
+      0xFF6A => self.obj_palettes.write_spec(value),
+      0xFF6B => {
+        if !self.palette_locked() {
+          self.obj_palettes.write_data(value);
+        }
+      },
+      0xFF70 => {
+        // Bank 0 can't be selected, it gets you bank 1
+        self.wram_bank = ::std::cmp::max(value & 0x07, 1) as usize;
+      },
+      _ => self.memory[address as usize] = value
+    }
+  }
+
+  fn read_cgb_register(&self, address: u16) -> u8 {
+    match address {
+      0xFF4D => {
+        let mut value = 0b0111_1110;
+        if self.double_speed {
+          value |= 0x80;
+        }
+        if self.speed_switch_armed {
+          value |= 0x01;
+        }
+        value
+      },
+      0xFF4F => 0b1111_1110 | self.vram_bank as u8,
+      0xFF51..=0xFF54 => 0xff,
+      0xFF55 => self.hdma.read_control(),
+      0xFF68 => self.bg_palettes.read_spec(),
+      0xFF69 => if self.palette_locked() { 0xff } else { self.bg_palettes.read_data() },
+      0xFF6A => self.obj_palettes.read_spec(),
+      0xFF6B => if self.palette_locked() { 0xff } else { self.obj_palettes.read_data() },
+      0xFF70 => 0b1111_1000 | self.wram_bank as u8,
+      _ => self.memory[address as usize]
+    }
+  }
+
+  fn step_dma(&mut self, cycles: i64) {
+    if let Some(mut dma) = self.dma.take() {
+      dma.cycles += cycles;
+      while dma.cycles >= 4 && dma.copied < DMA_LENGTH {
+        let value = self.read_bus(dma.source + dma.copied);
+        self.memory[0xFE00 + dma.copied as usize] = value;
+        dma.copied += 1;
+        dma.cycles -= 4;
+      }
+      if dma.copied < DMA_LENGTH {
+        self.dma = Some(dma);
+      }
+    }
+  }
+
+  pub fn set_button(&mut self, button: Button, pressed: bool) {
+    if self.joypad.set_button(button, pressed) {
+      self.request_interrupt(JOYPAD);
+    }
+  }
+
+  pub fn request_interrupt(&mut self, interrupt: InterruptFlags) {
+    self.memory[0xff0f] |= interrupt.bits();
+  }
+
   // @Performance Read and write can use unsafe operations to index
 
   pub fn write_byte(&mut self, address: u16, value: u8) {
-    self.memory[translate(address)] = value;
+    if self.locked_by_ppu(address) || self.locked_by_dma(address) {
+      return;
+    }
+    if address <= 0x7FFF {
+      self.cartridge.write_rom(address, value);
+    } else if address >= 0x8000 && address <= 0x9FFF {
+      self.write_vram(address, value);
+    } else if address >= 0xA000 && address <= 0xBFFF {
+      self.cartridge.write_ram(address, value);
+    } else if let Some(index) = self.wram_bank_address(address) {
+      self.wram_banks[index] = value;
+    } else if self.cgb && is_cgb_register(address) {
+      self.write_cgb_register(address, value);
+    } else if address == 0xFF00 {
+      if self.joypad.write(value) {
+        self.request_interrupt(JOYPAD);
+      }
+      if let Some(ref mut sgb) = self.sgb {
+        sgb.write_p1(value, &self.memory);
+      }
+    } else if address == 0xFF01 || address == 0xFF02 {
+      self.serial.write_byte(address, value);
+    } else if address >= 0xFF04 && address <= 0xFF07 {
+      self.timer.write_byte(address, value);
+    } else if address >= 0xFF10 && address <= 0xFF3F {
+      self.apu.write_byte(address, value);
+    } else if address == 0xFF41 {
+      // The mode and coincidence bits are read only
+      self.memory[0xff41] = (value & 0b0111_1000) | (self.memory[0xff41] & 0b0000_0111);
+    } else if address == 0xFF44 {
+      // LY is read only
+    } else if address == 0xFF50 {
+      // The last thing the boot ROM does is write here to hand over to the cartridge, and it's a one way trip
+      if value != 0 {
+        self.boot_rom = None;
+      }
+    } else if address == 0xFF46 {
+      self.memory[0xff46] = value;
+      // Sources past the end of working RAM hit the echo of it
+      let mut source = (value as u16) << 8;
+      if source >= 0xE000 {
+        source -= 0x2000;
+      }
+      self.dma = Some(Dma { source: source, copied: 0, cycles: 0 });
+    } else {
+      self.memory[translate(address)] = value;
+    }
   }
 
-  pub fn write_short(&mut self, address: u16, value: u16) {
-    // This is basically un-needed because rust does this in debug mode already
-    // but I just want to remind myself
-    debug_assert!(address != 65535);
-    self.memory[translate(address)] = value.lo();
-    self.memory[translate(address + 1)] = value.hi();
+  pub fn read_byte(&self, address: u16) -> u8 {
+    if self.locked_by_ppu(address) || self.locked_by_dma(address) {
+      return 0xff;
+    }
+    self.read_bus(address)
   }
 
-  pub fn read_byte(&self, address: u16) -> u8 {
-    if address >= 0xFEA0 && address <= 0xFEFF {
+  // Reads without any of the restrictions the CPU has
+  fn read_bus(&self, address: u16) -> u8 {
+    if let Some(ref boot_rom) = self.boot_rom {
+      // The CGB boot ROM is bigger, and leaves a gap for the cartridge header
+      if address < 0x100 || (address >= 0x200 && (address as usize) < boot_rom.len()) {
+        return boot_rom[address as usize];
+      }
+    }
+    if address <= 0x7FFF {
+      self.cartridge.read_rom(address)
+    } else if address >= 0x8000 && address <= 0x9FFF {
+      self.vram(self.vram_bank, address as usize)
+    } else if address >= 0xA000 && address <= 0xBFFF {
+      self.cartridge.read_ram(address)
+    } else if let Some(index) = self.wram_bank_address(address) {
+      self.wram_banks[index]
+    } else if self.cgb && is_cgb_register(address) {
+      self.read_cgb_register(address)
+    } else if address == 0xFF00 {
+      match self.sgb {
+        Some(ref sgb) => sgb.read_p1(self.joypad.read()),
+        None => self.joypad.read()
+      }
+    } else if address == 0xFF01 || address == 0xFF02 {
+      self.serial.read_byte(address)
+    } else if address >= 0xFF04 && address <= 0xFF07 {
+      self.timer.read_byte(address)
+    } else if address >= 0xFF10 && address <= 0xFF3F {
+      self.apu.read_byte(address)
+    } else if address >= 0xFEA0 && address <= 0xFEFF {
       0xff
     } else if address == 0xFF0F {
       0b11100000 | self.memory[0xff0f]
+    } else if address == 0xFF41 {
+      0b10000000 | self.memory[0xff41]
+    } else if address == 0xFF50 {
+      0xff
     } else {
       self.memory[translate(address)]
     }
   }
   
-  pub fn read_signed_byte(&self, address: u16) -> i8 {
-    self.read_byte(address) as i8
+  // The CPU can't get at VRAM during pixel transfer, or OAM during OAM search and pixel transfer
+  fn locked_by_ppu(&self, address: u16) -> bool {
+    if self.memory[0xff40] & 0x80 == 0 {
+      return false;
+    }
+    let mode = self.memory[0xff41] & 0b11;
+    if address >= 0x8000 && address <= 0x9FFF {
+      mode == 3
+    } else if address >= 0xFE00 && address <= 0xFE9F {
+      mode == 2 || mode == 3
+    } else {
+      false
+    }
+  }
+
+  // During OAM DMA the CPU can only use the I/O registers and HRAM, which are on its internal bus
+  fn locked_by_dma(&self, address: u16) -> bool {
+    self.dma.is_some() && address < 0xFF00
   }
+}
 
-  pub fn read_short(&self, address: u16) -> u16 {
-    // This is basically un-needed because rust does this in debug mode already
-    // but I just want to remind myself
-    debug_assert!(address != 65535);
-    (self.read_byte(address + 1) as u16) << 8 | self.read_byte(address) as u16
+fn is_cgb_register(address: u16) -> bool {
+  match address {
+    0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6B | 0xFF70 => true,
+    _ => false
   }
 }
 
@@ -0,0 +1,85 @@
+use image::Rgba;
+
+// The colours the four DMG shades are displayed as, from lightest (0) to darkest (3)
+#[derive(Clone, Copy)]
+pub struct Palette {
+  pub shades: [Rgba<u8>; 4]
+}
+
+impl Palette {
+  // The pea soup green of the original Game Boy
+  pub fn dmg() -> Palette {
+    Palette {
+      shades: [rgb(0x9bbc0f), rgb(0x8bac0f), rgb(0x306230), rgb(0x0f380f)]
+    }
+  }
+
+  // The Game Boy Pocket's greyscale screen
+  pub fn pocket() -> Palette {
+    Palette {
+      shades: [rgb(0xffffff), rgb(0xa9a9a9), rgb(0x545454), rgb(0x000000)]
+    }
+  }
+
+  // Reads `palette = dmg|pocket|custom` and, for custom,
+  // `palette_custom = #rrggbb, #rrggbb, #rrggbb, #rrggbb` from lightest to darkest
+  pub fn from_config(config: &[(String, String)]) -> Palette {
+    let mut palette = Palette::dmg();
+    let mut custom = None;
+    let mut use_custom = false;
+    for &(ref name, ref value) in config {
+      match &**name {
+        "palette" => {
+          match &*value.to_lowercase() {
+            "dmg" => palette = Palette::dmg(),
+            "pocket" => palette = Palette::pocket(),
+            "custom" => use_custom = true,
+            _ => warn!("Unknown palette {} in config", value)
+          }
+        },
+        "palette_custom" => {
+          custom = parse_custom(value);
+          if custom.is_none() {
+            warn!("Custom palette should be four #rrggbb colours, got {}", value);
+          }
+        },
+        _ => ()
+      }
+    }
+    if use_custom {
+      match custom {
+        Some(custom) => palette = custom,
+        None => warn!("Custom palette selected but palette_custom is missing or invalid, carrying on")
+      }
+    }
+    palette
+  }
+}
+
+fn parse_custom(value: &str) -> Option<Palette> {
+  let mut shades = [rgb(0); 4];
+  let mut count = 0;
+  for color in value.split(',') {
+    if count == 4 {
+      return None;
+    }
+    let color = color.trim().trim_start_matches('#');
+    if color.len() != 6 {
+      return None;
+    }
+    match u32::from_str_radix(color, 16) {
+      Ok(x) => shades[count] = rgb(x),
+      Err(_) => return None
+    }
+    count += 1;
+  }
+  if count == 4 {
+    Some(Palette { shades: shades })
+  } else {
+    None
+  }
+}
+
+fn rgb(color: u32) -> Rgba<u8> {
+  Rgba([(color >> 16) as u8, (color >> 8) as u8, color as u8, 255])
+}
@@ -1,8 +1,11 @@
 use memory::Memory;
 use image::{ImageBuffer, Rgba};
-use glium;
+use std::collections::VecDeque;
 use std::vec::Vec;
-use cpu::VBLANK;
+use cpu::{LCD_STAT, VBLANK};
+use palette::Palette;
+use cgb;
+use sgb;
 
 bitflags! {
     struct LCDC: u8 {
@@ -17,7 +20,118 @@ bitflags! {
     }
 }
 
+bitflags! {
+    struct STAT: u8 {
+        const LYC_INTERRUPT    = 0b01000000;
+        const OAM_INTERRUPT    = 0b00100000;
+        const VBLANK_INTERRUPT = 0b00010000;
+        const HBLANK_INTERRUPT = 0b00001000;
+        const COINCIDENCE      = 0b00000100;
+        const MODE             = 0b00000011;
+    }
+}
+
+bitflags! {
+    struct SpriteAttributes: u8 {
+        const BEHIND_BG      = 0b10000000;
+        const Y_FLIP         = 0b01000000;
+        const X_FLIP         = 0b00100000;
+        const SPRITE_PALETTE = 0b00010000;
+        const VRAM_BANK      = 0b00001000;
+        const CGB_PALETTE    = 0b00000111;
+    }
+}
+
+// CGB background map attributes, in VRAM bank 1 behind the tile numbers
+bitflags! {
+    struct BgAttributes: u8 {
+        const BG_PRIORITY = 0b10000000;
+        const BG_Y_FLIP   = 0b01000000;
+        const BG_X_FLIP   = 0b00100000;
+        const TILE_BANK   = 0b00001000;
+        const BG_PALETTE  = 0b00000111;
+    }
+}
+
+// An entry in OAM (0xFE00 - 0xFE9F), with coordinates in screen space
+#[derive(Clone, Copy)]
+struct Sprite {
+  y: i16,
+  x: i16,
+  tile: u8,
+  attributes: SpriteAttributes,
+  // Position in OAM, which is what decides priority on CGB
+  index: u8
+}
+
+// A pixel waiting in the background FIFO
 #[derive(Clone, Copy)]
+struct BgPixel {
+  color: u8,
+  attributes: BgAttributes
+}
+
+// A pixel waiting in the sprite FIFO. Colour 0 is transparent
+#[derive(Clone, Copy)]
+struct SpritePixel {
+  color: u8,
+  attributes: SpriteAttributes,
+  index: u8
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FetchStep {
+  Tile,
+  DataLow,
+  DataHigh,
+  // Waits for the background FIFO to empty out
+  Push
+}
+
+// Fetches a row of a background or window tile at a time for the background FIFO
+struct Fetcher {
+  step: FetchStep,
+  // Each step but pushing takes 2 dots
+  dots: u8,
+  window: bool,
+  // Which tile across the background or window we're on
+  tile_x: u8,
+  tile: u8,
+  attributes: BgAttributes,
+  row: u8,
+  low: u8,
+  high: u8
+}
+
+impl Fetcher {
+  fn new(window: bool) -> Fetcher {
+    Fetcher {
+      step: FetchStep::Tile,
+      dots: 0,
+      window: window,
+      tile_x: 0,
+      tile: 0,
+      attributes: BgAttributes::empty(),
+      row: 0,
+      low: 0,
+      high: 0
+    }
+  }
+}
+
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+// Every line is 456 dots, split between OAM search, pixel transfer and HBlank
+const OAM_SEARCH_CYCLES: i64 = 80;
+const LINE_CYCLES: i64 = 456;
+// 144 visible lines and 10 lines of VBlank
+pub const FRAME_CYCLES: i64 = LINE_CYCLES * 154;
+// The first tile of a line is fetched twice, the first time for nothing
+const FIRST_FETCH_DELAY: u8 = 6;
+// How long fetching a sprite holds up pixel transfer, once the background fetch it interrupts is done
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(Clone, Copy, PartialEq)]
 enum Mode {
   HBlank,
   VBlank,
@@ -25,138 +139,489 @@ enum Mode {
   PixelTransfer
 }
 
+pub const SCREEN_WIDTH: u32 = 160;
+pub const SCREEN_HEIGHT: u32 = 144;
+
 pub struct PPU {
-  // TODO: this can and should be a (boxed) [u8; 256] not a vec
+  // TODO: this can and should be a (boxed) [u8; 160 * 144] not a vec
+  // The last finished frame
   frame_buffer: ImageBuffer<Rgba<u8>, Vec<u8>>,
+  // The frame being drawn, which is swapped in at VBlank
+  back_buffer: ImageBuffer<Rgba<u8>, Vec<u8>>,
+  // Set when a frame is finished, until someone takes it
+  frame_done: bool,
   mode: Mode,
-  current_line: u8
+  current_line: u8,
+  // Dots into the current line
+  dots: i64,
+  // The window has its own line counter that only advances on lines it was drawn
+  window_line: u8,
+  // Sprites found by OAM search on the current line, in priority order
+  line_sprites: Vec<Sprite>,
+  sprite_fetched: [bool; MAX_SPRITES_PER_LINE],
+  palette: Palette,
+  lcd_on: bool,
+  // The first frame after the LCD is turned on isn't shown
+  skip_frame: bool,
+  // The STAT interrupt only fires when this goes from low to high,
+  // so one source being active blocks the others
+  stat_line: bool,
+  // Pixel transfer
+  fetcher: Fetcher,
+  bg_fifo: VecDeque<BgPixel>,
+  sprite_fifo: VecDeque<SpritePixel>,
+  // Pixels pushed out to the screen so far this line
+  lcd_x: u8,
+  // Pixels still to be thrown away at the start of the line, for SCX % 8
+  discard: u8,
+  transfer_delay: u8,
+  in_window: bool,
+  // The sprite being fetched and how long until it's done
+  fetching_sprite: Option<usize>,
+  sprite_fetch_dots: u8
 }
 
 impl PPU {
   pub fn new() -> PPU {
     PPU {
-      frame_buffer: ImageBuffer::new(256, 256),
+      frame_buffer: ImageBuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+      back_buffer: ImageBuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+      frame_done: false,
       mode: Mode::OAMSearch,
-      current_line: 0
+      current_line: 0,
+      dots: 0,
+      window_line: 0,
+      line_sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+      sprite_fetched: [false; MAX_SPRITES_PER_LINE],
+      palette: Palette::dmg(),
+      lcd_on: true,
+      skip_frame: false,
+      stat_line: false,
+      fetcher: Fetcher::new(false),
+      bg_fifo: VecDeque::with_capacity(16),
+      sprite_fifo: VecDeque::with_capacity(8),
+      lcd_x: 0,
+      discard: 0,
+      transfer_delay: 0,
+      in_window: false,
+      fetching_sprite: None,
+      sprite_fetch_dots: 0
     }
   }
 
-  pub fn draw(&mut self, memory: &Memory) -> (glium::texture::RawImage2d<u8>, u8) {
-    let control = LCDC::from_bits_truncate(memory.read_byte(0xff40));
-    let tiles = if control.contains(BG_WINDOW_TILESET) {
-      &memory.memory[0x8000..0x9000]
-    } else {
-      &memory.memory[0x8800..0x9800]
-    };
-    let bg_tile_map = if control.contains(BG_TILE_MAP) {
-      &memory.memory[0x9c00..0xa000]
-    } else {
-      &memory.memory[0x9800..0x9c00]
+  pub fn set_palette(&mut self, palette: Palette) {
+    self.palette = palette;
+  }
+
+  pub fn frame_buffer(&self) -> &ImageBuffer<Rgba<u8>, Vec<u8>> {
+    &self.frame_buffer
+  }
+
+  // True once if a frame has been finished since the last time this was called
+  pub fn take_frame_done(&mut self) -> bool {
+    let done = self.frame_done;
+    self.frame_done = false;
+    done
+  }
+
+  // The screen as it should be shown, inside the SGB border if there is one
+  pub fn draw(&self, memory: &Memory) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let sgb = match memory.sgb {
+      Some(ref sgb) => sgb,
+      None => return self.frame_buffer.clone()
     };
-    let mut cur_x = 0;
-    let mut cur_y = 0;
-    for row in bg_tile_map.chunks(32) {
-      for index in row {
-        let real_index = if control.contains(BG_WINDOW_TILESET) {
-          *index as usize * 16
-        } else {
-          ((*index) as i8 as i16 + 128) as usize * 16
-        };
-        let tile = &tiles[real_index..real_index+16];
-        for line in tile.chunks(2) {
-          self.frame_buffer.put_pixel(cur_x,     cur_y, to_pixel(line[0] & 0x80 >> 6 | line[1] & 0x80 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 1, cur_y, to_pixel(line[0] & 0x40 >> 6 | line[1] & 0x40 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 2, cur_y, to_pixel(line[0] & 0x20 >> 6 | line[1] & 0x20 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 3, cur_y, to_pixel(line[0] & 0x10 >> 6 | line[1] & 0x10 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 4, cur_y, to_pixel(line[0] & 0x08 >> 6 | line[1] & 0x08 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 5, cur_y, to_pixel(line[0] & 0x04 >> 6 | line[1] & 0x04 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 6, cur_y, to_pixel(line[0] & 0x02 >> 6 | line[1] & 0x02 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 7, cur_y, to_pixel(line[0] & 0x01 >> 6 | line[1] & 0x01 >> 7));
-          cur_y += 1;
+    // The SGB puts the screen in the middle of its border
+    let mut frame = ImageBuffer::new(sgb::BORDER_WIDTH, sgb::BORDER_HEIGHT);
+    for (x, y, pixel) in frame.enumerate_pixels_mut() {
+      let on_screen = x >= sgb::SCREEN_X && x < sgb::SCREEN_X + SCREEN_WIDTH && y >= sgb::SCREEN_Y && y < sgb::SCREEN_Y + SCREEN_HEIGHT;
+      *pixel = if on_screen {
+        *self.frame_buffer.get_pixel(x - sgb::SCREEN_X, y - sgb::SCREEN_Y)
+      } else {
+        colour_pixel(sgb.border_colour(x, y))
+      };
+    }
+    frame
+  }
+
+  // A DMG shade, coloured in by the SGB if there is one
+  fn shade_pixel(&self, memory: &Memory, x: u8, shade: u8) -> Rgba<u8> {
+    match memory.sgb {
+      Some(ref sgb) => colour_pixel(sgb.colour(x, self.current_line, shade)),
+      None => self.palette.shades[shade as usize]
+    }
+  }
+
+  // Finds the (up to 10) sprites on the current line
+  fn oam_search(&mut self, memory: &Memory) {
+    let control = LCDC::from_bits_truncate(memory.read_byte(0xff40));
+    let height = if control.contains(SPRITE_SIZE) { 16 } else { 8 };
+    let line = self.current_line as i16;
+    self.line_sprites.clear();
+    for (index, entry) in memory.memory[0xfe00..0xfea0].chunks(4).enumerate() {
+      let sprite = Sprite {
+        y: entry[0] as i16 - 16,
+        x: entry[1] as i16 - 8,
+        tile: entry[2],
+        attributes: SpriteAttributes::from_bits_truncate(entry[3]),
+        index: index as u8
+      };
+      if line >= sprite.y && line < sprite.y + height {
+        self.line_sprites.push(sprite);
+        if self.line_sprites.len() == MAX_SPRITES_PER_LINE {
+          break;
         }
-        cur_x += 8;
-        cur_y -= 8;
       }
-      cur_y += 8;
-      cur_x = 0;
     }
-    let scroll_x = memory.read_byte(0xff42);
-    let scroll_y = memory.read_byte(0xff43);
-    (glium::texture::RawImage2d::from_raw_rgba_reversed(self.frame_buffer.clone().into_raw(), (256, 256)), 0)
+    // The sprite with the smaller X wins, OAM order breaks ties (the sort is stable).
+    // CGB goes by OAM order alone
+    if !memory.cgb {
+      self.line_sprites.sort_by_key(|sprite| sprite.x);
+    }
+    self.sprite_fetched = [false; MAX_SPRITES_PER_LINE];
+  }
+
+  // Runs for this many (normal speed) cycles, a dot at a time
+  pub fn tick(&mut self, memory: &mut Memory, cycles: i64) {
+    let control = LCDC::from_bits_truncate(memory.read_byte(0xff40));
+    if !control.contains(LCD_POWER) {
+      if self.lcd_on {
+        self.turn_off(memory);
+      }
+      return;
+    } else if !self.lcd_on {
+      self.lcd_on = true;
+      self.skip_frame = true;
+      self.mode = Mode::OAMSearch;
+      self.dots = 0;
+      self.update_stat(memory);
+    }
+    for _ in 0..cycles {
+      self.dot(memory);
+    }
   }
 
-  pub fn step(&mut self, memory: &mut Memory) -> i64 {
+  fn dot(&mut self, memory: &mut Memory) {
+    self.dots += 1;
     match self.mode {
       Mode::OAMSearch => {
-        self.mode = Mode::PixelTransfer;
-        memory.memory[0xff41] = (memory.memory[0xff41] & 0xFC) | Mode::PixelTransfer as u8;
-        80
+        if self.dots == OAM_SEARCH_CYCLES {
+          self.oam_search(memory);
+          self.start_pixel_transfer(memory);
+          self.mode = Mode::PixelTransfer;
+          self.update_stat(memory);
+        }
       },
       Mode::PixelTransfer => {
-        self.mode = Mode::HBlank;
-        memory.memory[0xff41] = memory.memory[0xff41] & 0xFC;
-        172 // This number is WRONG, in actuality this depends on stuff
-      },
-      Mode::HBlank => {
-        self.current_line += 1;
-        if self.current_line == 144 {
-          self.mode = Mode::VBlank;
-          memory.memory[0xff41] = (memory.memory[0xff41] & 0xFC) | Mode::VBlank as u8;
-          memory.memory[0xff40] |= VBLANK.bits();
-        } else {
-          self.mode = Mode::OAMSearch;
-          memory.memory[0xff41] = (memory.memory[0xff41] & 0xFC) | Mode::OAMSearch as u8;
+        self.transfer_dot(memory);
+        // Pixel transfer lasts as long as it takes to push out the whole line
+        if self.lcd_x as u32 == SCREEN_WIDTH {
+          if self.in_window {
+            self.window_line += 1;
+          }
+          self.mode = Mode::HBlank;
+          memory.hblank();
+          self.update_stat(memory);
         }
-        memory.memory[0xff44] = self.current_line;
-        204
       },
-      Mode::VBlank => {
-        self.current_line += 1;
-        if self.current_line == 154 {
-          self.current_line = 0;
-          self.mode = Mode::OAMSearch;
-          memory.memory[0xff41] = (memory.memory[0xff41] & 0xFC) | Mode::OAMSearch as u8;
-          memory.memory[0xff40] &= !VBLANK.bits();
+      Mode::HBlank | Mode::VBlank => ()
+    }
+    if self.dots == LINE_CYCLES {
+      self.dots = 0;
+      self.next_line(memory);
+    }
+  }
+
+  fn next_line(&mut self, memory: &mut Memory) {
+    self.current_line += 1;
+    if self.current_line == 144 {
+      self.mode = Mode::VBlank;
+      memory.request_interrupt(VBLANK);
+      // The frame after the LCD is turned on is never shown, the screen stays blank
+      if !self.skip_frame {
+        ::std::mem::swap(&mut self.frame_buffer, &mut self.back_buffer);
+      }
+      self.frame_done = true;
+    } else if self.current_line == 154 {
+      self.current_line = 0;
+      self.window_line = 0;
+      self.skip_frame = false;
+      self.mode = Mode::OAMSearch;
+    } else if self.current_line < 144 {
+      self.mode = Mode::OAMSearch;
+    }
+    self.update_stat(memory);
+  }
+
+  fn start_pixel_transfer(&mut self, memory: &Memory) {
+    self.fetcher = Fetcher::new(false);
+    self.bg_fifo.clear();
+    self.sprite_fifo.clear();
+    self.lcd_x = 0;
+    // Fine scroll is done by throwing away the first few pixels
+    self.discard = memory.read_byte(0xff43) % 8;
+    self.transfer_delay = FIRST_FETCH_DELAY;
+    self.in_window = false;
+    self.fetching_sprite = None;
+    self.sprite_fetch_dots = 0;
+  }
+
+  // One dot of pixel transfer. Registers are read as they're needed, so games can change them mid-line
+  fn transfer_dot(&mut self, memory: &Memory) {
+    if self.transfer_delay > 0 {
+      self.transfer_delay -= 1;
+      return;
+    }
+    let control = LCDC::from_bits_truncate(memory.read_byte(0xff40));
+
+    // Everything waits while a sprite is fetched
+    if let Some(sprite) = self.fetching_sprite {
+      self.sprite_fetch_dots -= 1;
+      if self.sprite_fetch_dots == 0 {
+        self.fetch_sprite(memory, control, sprite);
+        self.fetching_sprite = None;
+      }
+      return;
+    }
+
+    // Starting the window throws away the background and restarts the fetcher
+    // WX is offset by 7, so 7 is the left edge of the screen
+    let window_y = memory.read_byte(0xff4a);
+    let window_x = memory.read_byte(0xff4b);
+    if !self.in_window && control.contains(WINDOW_ENABLE) && self.current_line >= window_y &&
+      window_x < SCREEN_WIDTH as u8 + 7 && self.lcd_x as u16 + 7 >= window_x as u16 {
+      self.in_window = true;
+      self.fetcher = Fetcher::new(true);
+      self.bg_fifo.clear();
+      self.discard = 7u8.saturating_sub(window_x);
+    }
+
+    // A sprite starting here has to be fetched, but only once the background fetch in progress is done
+    if self.discard == 0 && control.contains(SPRITES_ENABLED) {
+      if let Some(sprite) = self.due_sprite() {
+        if self.fetcher.step == FetchStep::Push {
+          self.sprite_fetched[sprite] = true;
+          self.fetching_sprite = Some(sprite);
+          self.sprite_fetch_dots = SPRITE_FETCH_DOTS;
+        } else {
+          self.fetch_dot(memory, control);
         }
-        memory.memory[0xff44] = self.current_line;
-        456 // this should vary based on line
+        return;
       }
     }
+
+    self.fetch_dot(memory, control);
+    self.push_pixel(memory, control);
   }
 
-  pub fn estimate_clock_cycles(&mut self) -> i64 {
-    match self.mode {
-      Mode::OAMSearch => {
-        80
+  // The first sprite on the line that starts at or before the next pixel and hasn't been fetched
+  fn due_sprite(&self) -> Option<usize> {
+    for (i, sprite) in self.line_sprites.iter().enumerate() {
+      if !self.sprite_fetched[i] && sprite.x <= self.lcd_x as i16 {
+        return Some(i);
+      }
+    }
+    None
+  }
+
+  fn fetch_dot(&mut self, memory: &Memory, control: LCDC) {
+    if self.fetcher.step == FetchStep::Push {
+      if self.bg_fifo.is_empty() {
+        for column in 0..8 {
+          let bit = if self.fetcher.attributes.contains(BG_X_FLIP) { column } else { 7 - column };
+          let color = ((self.fetcher.high >> bit) & 1) << 1 | (self.fetcher.low >> bit) & 1;
+          self.bg_fifo.push_back(BgPixel { color: color, attributes: self.fetcher.attributes });
+        }
+        self.fetcher.tile_x = self.fetcher.tile_x.wrapping_add(1);
+        self.fetcher.step = FetchStep::Tile;
+      }
+      return;
+    }
+    self.fetcher.dots += 1;
+    if self.fetcher.dots < 2 {
+      return;
+    }
+    self.fetcher.dots = 0;
+    match self.fetcher.step {
+      FetchStep::Tile => {
+        let (map_address, y) = if self.fetcher.window {
+          let tile_map = if control.contains(WINDOW_TILE_MAP) { 0x9c00 } else { 0x9800 };
+          (tile_map + (self.window_line as usize / 8) * 32 + (self.fetcher.tile_x & 31) as usize, self.window_line)
+        } else {
+          let tile_map = if control.contains(BG_TILE_MAP) { 0x9c00 } else { 0x9800 };
+          let y = self.current_line.wrapping_add(memory.read_byte(0xff42));
+          let x = (memory.read_byte(0xff43) / 8).wrapping_add(self.fetcher.tile_x) & 31;
+          (tile_map + (y as usize / 8) * 32 + x as usize, y)
+        };
+        self.fetcher.tile = memory.vram(0, map_address);
+        // CGB keeps the tile's attributes in bank 1, behind the tile number
+        self.fetcher.attributes = if memory.cgb {
+          BgAttributes::from_bits_truncate(memory.vram(1, map_address))
+        } else {
+          BgAttributes::empty()
+        };
+        self.fetcher.row = if self.fetcher.attributes.contains(BG_Y_FLIP) { 7 - y % 8 } else { y % 8 };
+        self.fetcher.step = FetchStep::DataLow;
       },
-      Mode::PixelTransfer => {
-        172
+      FetchStep::DataLow => {
+        self.fetcher.low = self.tile_data(memory, control, 0);
+        self.fetcher.step = FetchStep::DataHigh;
       },
-      Mode::HBlank => {
-        204
+      FetchStep::DataHigh => {
+        self.fetcher.high = self.tile_data(memory, control, 1);
+        self.fetcher.step = FetchStep::Push;
       },
-      Mode::VBlank => {
-        456
+      FetchStep::Push => unreachable!()
+    }
+  }
+
+  // A byte of the row of the tile the fetcher is on
+  fn tile_data(&self, memory: &Memory, control: LCDC, byte: usize) -> u8 {
+    let tile = if control.contains(BG_WINDOW_TILESET) {
+      0x8000 + self.fetcher.tile as usize * 16
+    } else {
+      // Signed indexing from 0x9000
+      (0x9000 + self.fetcher.tile as i8 as isize * 16) as usize
+    };
+    let bank = if self.fetcher.attributes.contains(TILE_BANK) { 1 } else { 0 };
+    memory.vram(bank, tile + self.fetcher.row as usize * 2 + byte)
+  }
+
+  // Loads a row of a sprite into the sprite FIFO, under any sprite pixels already there
+  fn fetch_sprite(&mut self, memory: &Memory, control: LCDC, sprite: usize) {
+    let sprite = self.line_sprites[sprite];
+    let tall = control.contains(SPRITE_SIZE);
+    let height = if tall { 16 } else { 8 };
+    // OAM search went by the size at the time, which a game can change before the sprite is fetched
+    let mut row = (self.current_line as i16 - sprite.y) as u8 & (height - 1);
+    if sprite.attributes.contains(Y_FLIP) {
+      row = height - 1 - row;
+    }
+    // In 8x16 mode the top tile is always even
+    let tile = if tall { sprite.tile & 0xFE } else { sprite.tile };
+    let bank = if memory.cgb && sprite.attributes.contains(VRAM_BANK) { 1 } else { 0 };
+    let line = 0x8000 + tile as usize * 16 + row as usize * 2;
+    let low = memory.vram(bank, line);
+    let high = memory.vram(bank, line + 1);
+    // Sprites hanging off the left of the screen have already lost their first few pixels
+    let skip = (self.lcd_x as i16 - sprite.x) as u8;
+    for column in skip..8 {
+      let bit = if sprite.attributes.contains(X_FLIP) { column } else { 7 - column };
+      let pixel = SpritePixel {
+        color: ((high >> bit) & 1) << 1 | (low >> bit) & 1,
+        attributes: sprite.attributes,
+        index: sprite.index
+      };
+      let position = (column - skip) as usize;
+      if position < self.sprite_fifo.len() {
+        // Whatever got there first wins unless it's transparent. On CGB a lower OAM index wins anyway
+        let existing = self.sprite_fifo[position];
+        if pixel.color != 0 && (existing.color == 0 || (memory.cgb && pixel.index < existing.index)) {
+          self.sprite_fifo[position] = pixel;
+        }
+      } else {
+        self.sprite_fifo.push_back(pixel);
+      }
+    }
+  }
+
+  // Pushes a pixel out to the screen, if the background FIFO has one
+  fn push_pixel(&mut self, memory: &Memory, control: LCDC) {
+    let bg = match self.bg_fifo.pop_front() {
+      Some(bg) => bg,
+      None => return
+    };
+    if self.discard > 0 {
+      self.discard -= 1;
+      return;
+    }
+    let sprite = self.sprite_fifo.pop_front();
+    let x = self.lcd_x;
+    let pixel = self.mix_pixel(memory, control, x, bg, sprite);
+    self.back_buffer.put_pixel(x as u32, self.current_line as u32, pixel);
+    self.lcd_x += 1;
+  }
+
+  // Decides between the background and sprite pixel, and looks up its colour
+  fn mix_pixel(&self, memory: &Memory, control: LCDC, x: u8, bg: BgPixel, sprite: Option<SpritePixel>) -> Rgba<u8> {
+    // On DMG the background enable bit turns off the window too.
+    // On CGB it only takes away the background's priority over sprites
+    let bg_color = if memory.cgb || control.contains(BG_ENABLED) { bg.color } else { 0 };
+    if let Some(sprite) = sprite {
+      if sprite.color != 0 && control.contains(SPRITES_ENABLED) {
+        let bg_wins = if memory.cgb {
+          control.contains(BG_ENABLED) && (sprite.attributes.contains(BEHIND_BG) || bg.attributes.contains(BG_PRIORITY))
+        } else {
+          sprite.attributes.contains(BEHIND_BG)
+        };
+        if !bg_wins || bg_color == 0 {
+          if memory.cgb {
+            let palette = (sprite.attributes & CGB_PALETTE).bits();
+            return colour_pixel(memory.obj_palettes.colour(palette, sprite.color));
+          }
+          let palette = if sprite.attributes.contains(SPRITE_PALETTE) {
+            memory.read_byte(0xff49)
+          } else {
+            memory.read_byte(0xff48)
+          };
+          return self.shade_pixel(memory, x, to_shade(palette, sprite.color));
+        }
       }
     }
+    if memory.cgb {
+      let palette = (bg.attributes & BG_PALETTE).bits();
+      colour_pixel(memory.bg_palettes.colour(palette, bg_color))
+    } else {
+      self.shade_pixel(memory, x, to_shade(memory.read_byte(0xff47), bg_color))
+    }
+  }
+
+  // The PPU stops entirely while the LCD is off, with LY stuck at 0 and a blank screen
+  fn turn_off(&mut self, memory: &mut Memory) {
+    self.lcd_on = false;
+    self.current_line = 0;
+    self.dots = 0;
+    self.window_line = 0;
+    self.mode = Mode::HBlank;
+    self.stat_line = false;
+    memory.memory[0xff44] = 0;
+    memory.memory[0xff41] &= !(MODE | COINCIDENCE).bits();
+    let blank = if memory.cgb {
+      Rgba([0xff, 0xff, 0xff, 0xff])
+    } else {
+      self.palette.shades[0]
+    };
+    for pixel in self.frame_buffer.pixels_mut() {
+      *pixel = blank;
+    }
+  }
+
+  // Updates LY and STAT, and requests the STAT interrupt if one of its enabled sources just became active
+  fn update_stat(&mut self, memory: &mut Memory) {
+    memory.memory[0xff44] = self.current_line;
+    let mut stat = STAT::from_bits_truncate(memory.memory[0xff41]);
+    stat.remove(MODE);
+    stat.insert(STAT::from_bits_truncate(self.mode as u8));
+    stat.set(COINCIDENCE, self.current_line == memory.memory[0xff45]);
+    memory.memory[0xff41] = stat.bits();
+
+    let line = (stat.contains(COINCIDENCE) && stat.contains(LYC_INTERRUPT)) ||
+      (self.mode == Mode::HBlank && stat.contains(HBLANK_INTERRUPT)) ||
+      (self.mode == Mode::VBlank && stat.contains(VBLANK_INTERRUPT)) ||
+      (self.mode == Mode::OAMSearch && stat.contains(OAM_INTERRUPT));
+    if line && !self.stat_line {
+      memory.request_interrupt(LCD_STAT);
+    }
+    self.stat_line = line;
   }
 }
 
-fn to_pixel(bits: u8) -> Rgba<u8> {
-  // TODO: do palette lookup
-  match bits {
-    0 => {
-      Rgba([0u8, 0, 0, 255])
-    },
-    1 => {
-      Rgba([85u8, 85, 85, 255])
-    },
-    2 => {
-      Rgba([170u8, 170, 170, 255])
-    },
-    3 => {
-      Rgba([255u8, 255, 255, 255])
-    },
-    _ => unreachable!()
-  }
-}
\ No newline at end of file
+// Both CGB and SGB colours are 15 bit BGR
+fn colour_pixel(colour: u16) -> Rgba<u8> {
+  let rgb = cgb::to_rgb(colour);
+  Rgba([rgb[0], rgb[1], rgb[2], 0xff])
+}
+
+// Looks up a colour number in a BGP/OBP0/OBP1 style palette register
+fn to_shade(palette: u8, color: u8) -> u8 {
+  (palette >> (color * 2)) & 0b11
+}
@@ -1,13 +1,35 @@
 use memory::Memory;
+use cartridge::{Cartridge, Controller};
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::path::PathBuf;
 use enum_primitive::FromPrimitive;
 
-const SUPPORTED_CART_TYPES: [Cart; 1] = [Cart::RomOnly];
+const SUPPORTED_CART_TYPES: [Cart; 19] = [
+  Cart::RomOnly,
+  Cart::MBC1,
+  Cart::BC1Ram,
+  Cart::BC1RamBatt,
+  Cart::MBC2,
+  Cart::MBC2Batt,
+  Cart::Ram,
+  Cart::RamBatt,
+  Cart::MBC3TimerBatt,
+  Cart::MBC3TimerRamBatt,
+  Cart::MBC3,
+  Cart::MBC3Ram,
+  Cart::MBC3RamBatt,
+  Cart::MBC5,
+  Cart::MBC5Ram,
+  Cart::MBC5RamBatt,
+  Cart::MBC5Rumble,
+  Cart::MBC5RumbleRam,
+  Cart::BC5RumbleRamBatt
+];
 
 enum_from_primitive! {
-  #[derive(PartialEq)]
+  #[derive(Clone, Copy, PartialEq)]
   enum Cart {
     RomOnly = 0x00,
     MBC1,
@@ -42,8 +64,14 @@ enum_from_primitive! {
 
 #[derive(Debug)]
 pub enum RomError {
-  NotARom,
-  Io(io::Error)
+  Io(io::Error),
+  // The file isn't even big enough to hold a header
+  Truncated { size: usize },
+  BadHeaderChecksum { expected: u8, actual: u8 },
+  BadGlobalChecksum { expected: u16, actual: u16 },
+  // The file size doesn't match the ROM size the header declares
+  SizeMismatch { expected: usize, actual: usize },
+  UnsupportedMapper(u8)
 }
 
 impl From<io::Error> for RomError {
@@ -52,25 +80,200 @@ impl From<io::Error> for RomError {
   }
 }
 
-pub fn load_rom(memory: &mut Memory, path: &str) -> Result<(), RomError> {
-  do_load(memory, path)?;
-  let cart_val = memory.read_byte(0x1047);
-  let cart_type = Cart::from_u8(cart_val);
-  if let Some(cart) = cart_type {
-    if !is_supported(cart) {
-      println!("ROM {:02x} not supported, but carrying on", cart_val);
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CgbSupport {
+  // DMG only
+  None,
+  // Works on both DMG and CGB
+  Enhanced,
+  // CGB only
+  Required
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Destination {
+  Japanese,
+  NonJapanese
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Licensee {
+  // 0x014B, used by older games
+  Old(u8),
+  // 0x0144 - 0x0145, used when the old code is 0x33
+  New(String)
+}
+
+// 0x0100 - 0x014F
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+  pub title: String,
+  pub manufacturer_code: Option<String>,
+  pub cgb: CgbSupport,
+  pub sgb: bool,
+  pub licensee: Licensee,
+  pub cart_type: u8,
+  pub rom_size_code: u8,
+  pub ram_size_code: u8,
+  pub destination: Destination,
+  pub version: u8,
+  pub header_checksum: u8,
+  pub global_checksum: u16
+}
+
+impl CartridgeHeader {
+  pub fn parse(rom: &[u8]) -> Result<CartridgeHeader, RomError> {
+    if rom.len() < 0x150 {
+      return Err(RomError::Truncated { size: rom.len() });
+    }
+
+    let actual_checksum = rom[0x0134..0x014D].iter().fold(0u8, |acc, x| acc.wrapping_sub(*x).wrapping_sub(1));
+    if actual_checksum != rom[0x014D] {
+      return Err(RomError::BadHeaderChecksum { expected: rom[0x014D], actual: actual_checksum });
+    }
+
+    let cgb = match rom[0x0143] {
+      0x80 => CgbSupport::Enhanced,
+      0xC0 => CgbSupport::Required,
+      _ => CgbSupport::None
+    };
+    // Newer carts steal the end of the title area for the manufacturer code and CGB flag
+    let (title, manufacturer_code) = if cgb == CgbSupport::None {
+      (&rom[0x0134..0x0144], None)
+    } else {
+      let manufacturer = &rom[0x013F..0x0143];
+      if manufacturer.iter().all(|x| (*x as char).is_ascii_uppercase()) {
+        (&rom[0x0134..0x013F], Some(to_string(manufacturer)))
+      } else {
+        (&rom[0x0134..0x0143], None)
+      }
+    };
+    let licensee = if rom[0x014B] == 0x33 {
+      Licensee::New(to_string(&rom[0x0144..0x0146]))
+    } else {
+      Licensee::Old(rom[0x014B])
+    };
+
+    Ok(CartridgeHeader {
+      title: to_string(title),
+      manufacturer_code: manufacturer_code,
+      cgb: cgb,
+      sgb: rom[0x0146] == 0x03,
+      licensee: licensee,
+      cart_type: rom[0x0147],
+      rom_size_code: rom[0x0148],
+      ram_size_code: rom[0x0149],
+      destination: if rom[0x014A] == 0x00 { Destination::Japanese } else { Destination::NonJapanese },
+      version: rom[0x014C],
+      header_checksum: rom[0x014D],
+      global_checksum: (rom[0x014E] as u16) << 8 | rom[0x014F] as u16
+    })
+  }
+
+  // The global checksum is the sum of every byte in the ROM except the checksum itself.
+  // Real hardware never checks this, so plenty of homebrew gets it wrong
+  pub fn verify_global_checksum(&self, rom: &[u8]) -> Result<(), RomError> {
+    let sum = rom.iter().fold(0u16, |acc, x| acc.wrapping_add(*x as u16));
+    let actual = sum.wrapping_sub(rom[0x014E] as u16).wrapping_sub(rom[0x014F] as u16);
+    if actual != self.global_checksum {
+      return Err(RomError::BadGlobalChecksum { expected: self.global_checksum, actual: actual });
+    }
+    Ok(())
+  }
+
+  // Checks the file is as big as the header says. Dumps are often padded or trimmed, so this isn't fatal either
+  pub fn verify_size(&self, rom: &[u8]) -> Result<(), RomError> {
+    if let Some(expected) = self.rom_size() {
+      if expected != rom.len() {
+        return Err(RomError::SizeMismatch { expected: expected, actual: rom.len() });
+      }
     }
     Ok(())
-  } else {
-    println!("ROM given has invalid type {:02x}!", cart_val);
-    Err(RomError::NotARom)
+  }
+
+  // In bytes, None if the code is unknown
+  pub fn rom_size(&self) -> Option<usize> {
+    match self.rom_size_code {
+      0x00..=0x08 => Some(0x8000 << self.rom_size_code),
+      0x52 => Some(0x120000),
+      0x53 => Some(0x140000),
+      0x54 => Some(0x180000),
+      _ => None
+    }
+  }
+
+  // In bytes
+  pub fn ram_size(&self) -> usize {
+    match self.ram_size_code {
+      0x01 => 0x800,
+      0x02 => 0x2000,
+      0x03 => 0x8000,
+      0x04 => 0x20000,
+      0x05 => 0x10000,
+      _ => 0
+    }
+  }
+}
+
+impl Cart {
+  fn controller(&self) -> Controller {
+    match *self {
+      Cart::MBC1 | Cart::BC1Ram | Cart::BC1RamBatt => Controller::MBC1,
+      Cart::MBC2 | Cart::MBC2Batt => Controller::MBC2,
+      Cart::MBC3TimerBatt | Cart::MBC3TimerRamBatt | Cart::MBC3 | Cart::MBC3Ram | Cart::MBC3RamBatt => Controller::MBC3,
+      Cart::MBC5 | Cart::MBC5Ram | Cart::MBC5RamBatt | Cart::MBC5Rumble | Cart::MBC5RumbleRam | Cart::BC5RumbleRamBatt => Controller::MBC5,
+      _ => Controller::None
+    }
+  }
+
+  fn has_timer(&self) -> bool {
+    *self == Cart::MBC3TimerBatt || *self == Cart::MBC3TimerRamBatt
+  }
+
+  fn has_battery(&self) -> bool {
+    match *self {
+      Cart::BC1RamBatt | Cart::MBC2Batt | Cart::RamBatt | Cart::MMM01RamBatt | Cart::MBC3TimerBatt |
+      Cart::MBC3TimerRamBatt | Cart::MBC3RamBatt | Cart::MBC5RamBatt | Cart::BC5RumbleRamBatt |
+      Cart::MBC7SensorRumbleRamBatt | Cart::HudsonHuC1RamBatt => true,
+      _ => false
+    }
   }
 }
 
-fn do_load(memory: &mut Memory, path: &str) -> Result<(), io::Error> {
+pub fn load_rom(memory: &mut Memory, path: &str, save_path: PathBuf) -> Result<CartridgeHeader, RomError> {
+  let mut rom = do_load(path)?;
+  let header = CartridgeHeader::parse(&rom)?;
+  let cart = match Cart::from_u8(header.cart_type) {
+    Some(cart) if is_supported(cart) => cart,
+    _ => return Err(RomError::UnsupportedMapper(header.cart_type))
+  };
+  if let Err(RomError::BadGlobalChecksum { expected, actual }) = header.verify_global_checksum(&rom) {
+    warn!("ROM global checksum is {:04x} but header says {:04x}, carrying on", actual, expected);
+  }
+  if let Err(RomError::SizeMismatch { expected, actual }) = header.verify_size(&rom) {
+    warn!("ROM is {} bytes but header says {}, going with the header", actual, expected);
+    rom.resize(expected, 0xff);
+  }
+  memory.cartridge = Cartridge::new(rom, cart.controller(), header.ram_size());
+  if cart.has_timer() {
+    memory.cartridge.add_rtc();
+  }
+  if cart.has_battery() {
+    memory.cartridge.attach_save(save_path)?;
+  }
+  Ok(header)
+}
+
+fn do_load(path: &str) -> Result<Vec<u8>, io::Error> {
   let mut file = File::open(path)?;
-  let buf = &mut memory.memory[0..0x8000];
-  file.read_exact(buf)
+  let mut buf = Vec::new();
+  file.read_to_end(&mut buf)?;
+  Ok(buf)
+}
+
+// Header strings are NUL padded ASCII
+fn to_string(bytes: &[u8]) -> String {
+  bytes.iter().take_while(|x| **x != 0).map(|x| *x as char).collect()
 }
 
 fn is_supported(cart: Cart) -> bool {
@@ -81,3 +284,115 @@ fn is_supported(cart: Cart) -> bool {
   }
   false
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::env;
+  use std::fs;
+  use std::io::Write;
+
+  // A 32KB MBC1 ROM with a valid header and checksums
+  fn rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x0134..0x0138].copy_from_slice(b"TEST");
+    rom[0x0147] = 0x01;
+    fix_checksums(&mut rom);
+    rom
+  }
+
+  fn fix_checksums(rom: &mut [u8]) {
+    rom[0x014D] = rom[0x0134..0x014D].iter().fold(0u8, |acc, x| acc.wrapping_sub(*x).wrapping_sub(1));
+    rom[0x014E] = 0;
+    rom[0x014F] = 0;
+    let sum = rom.iter().fold(0u16, |acc, x| acc.wrapping_add(*x as u16));
+    rom[0x014E] = (sum >> 8) as u8;
+    rom[0x014F] = sum as u8;
+  }
+
+  #[test]
+  fn parses_header() {
+    let header = CartridgeHeader::parse(&rom()).unwrap();
+    assert_eq!(header.title, "TEST");
+    assert_eq!(header.cart_type, 0x01);
+    assert_eq!(header.rom_size(), Some(0x8000));
+    assert_eq!(header.cgb, CgbSupport::None);
+    assert!(header.verify_global_checksum(&rom()).is_ok());
+    assert!(header.verify_size(&rom()).is_ok());
+  }
+
+  #[test]
+  fn bad_header_checksum() {
+    let mut rom = rom();
+    let checksum = rom[0x014D];
+    rom[0x0134] = b'B';
+    match CartridgeHeader::parse(&rom) {
+      Err(RomError::BadHeaderChecksum { expected, .. }) => assert_eq!(expected, checksum),
+      _ => panic!("header checksum wasn't checked")
+    }
+  }
+
+  #[test]
+  fn truncated() {
+    match CartridgeHeader::parse(&rom()[..0x14F]) {
+      Err(RomError::Truncated { size }) => assert_eq!(size, 0x14F),
+      _ => panic!("truncated header was parsed")
+    }
+  }
+
+  #[test]
+  fn bad_global_checksum() {
+    let mut rom = rom();
+    let header = CartridgeHeader::parse(&rom).unwrap();
+    rom[0x4000] = 0x01;
+    match header.verify_global_checksum(&rom) {
+      Err(RomError::BadGlobalChecksum { expected, actual }) => assert_eq!(actual, expected.wrapping_add(1)),
+      _ => panic!("global checksum wasn't checked")
+    }
+  }
+
+  #[test]
+  fn size_mismatch() {
+    let mut rom = rom();
+    let header = CartridgeHeader::parse(&rom).unwrap();
+    rom.resize(0xC000, 0);
+    match header.verify_size(&rom) {
+      Err(RomError::SizeMismatch { expected, actual }) => {
+        assert_eq!(expected, 0x8000);
+        assert_eq!(actual, 0xC000);
+      },
+      _ => panic!("size wasn't checked")
+    }
+  }
+
+  #[test]
+  fn load_goes_with_header_size() {
+    // An extra bank on the end that the header doesn't know about
+    let mut rom = rom();
+    rom.resize(0xC000, 0x22);
+    let path = env::temp_dir().join("bamegoy_size_mismatch.gb");
+    File::create(&path).unwrap().write_all(&rom).unwrap();
+    let mut memory = Memory::new();
+    let result = load_rom(&mut memory, path.to_str().unwrap(), path.with_extension("sav"));
+    fs::remove_file(&path).unwrap();
+    result.unwrap();
+    // Bank 2 wraps round to bank 0 rather than picking up the extra data
+    memory.cartridge.write_rom(0x2000, 0x02);
+    assert_eq!(memory.cartridge.read_rom(0x4000), 0x00);
+  }
+
+  #[test]
+  fn unsupported_mapper() {
+    let mut rom = rom();
+    rom[0x0147] = 0x20;
+    fix_checksums(&mut rom);
+    let path = env::temp_dir().join("bamegoy_unsupported_mapper.gb");
+    File::create(&path).unwrap().write_all(&rom).unwrap();
+    let result = load_rom(&mut Memory::new(), path.to_str().unwrap(), path.with_extension("sav"));
+    fs::remove_file(&path).unwrap();
+    match result {
+      Err(RomError::UnsupportedMapper(0x20)) => (),
+      _ => panic!("MBC6 was loaded")
+    }
+  }
+}
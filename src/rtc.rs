@@ -0,0 +1,245 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The trailer appended to save files, in the format shared by most emulators:
+// current S, M, H, DL, DH and then latched S, M, H, DL, DH as little endian u32s,
+// followed by the unix timestamp the save was made as a little endian u64
+pub const SAVE_SIZE: usize = 48;
+// Some emulators write the timestamp as a u32 instead
+pub const SHORT_SAVE_SIZE: usize = 44;
+
+const DAY_HIGH_BIT: u8 = 0b0000_0001;
+const HALT: u8 = 0b0100_0000;
+const DAY_CARRY: u8 = 0b1000_0000;
+
+// MBC3 real time clock
+pub struct Rtc {
+  seconds: u8,
+  minutes: u8,
+  hours: u8,
+  days: u16, // 9 bits
+  halted: bool,
+  day_carry: bool,
+  // Registers as they were at the last latch, which is what the game reads
+  latched: [u8; 5],
+  // A latch happens on writing 0x00 then 0x01
+  latch_primed: bool,
+  // Unix time (in seconds) the registers are current as of
+  last_update: u64
+}
+
+impl Rtc {
+  pub fn new() -> Rtc {
+    Rtc {
+      seconds: 0,
+      minutes: 0,
+      hours: 0,
+      days: 0,
+      halted: false,
+      day_carry: false,
+      latched: [0; 5],
+      latch_primed: false,
+      last_update: now()
+    }
+  }
+
+  // 0x6000 - 0x7FFF
+  pub fn write_latch(&mut self, value: u8) {
+    if self.latch_primed && value == 0x01 {
+      self.update();
+      self.latched = self.registers();
+    }
+    self.latch_primed = value == 0x00;
+  }
+
+  // Register is the RAM bank, 0x08 - 0x0C
+  pub fn read(&self, register: u8) -> u8 {
+    self.latched[(register - 0x08) as usize]
+  }
+
+  // Register is the RAM bank, 0x08 - 0x0C
+  pub fn write(&mut self, register: u8, value: u8) {
+    self.update();
+    match register {
+      0x08 => self.seconds = value & 0x3f,
+      0x09 => self.minutes = value & 0x3f,
+      0x0A => self.hours = value & 0x1f,
+      0x0B => self.days = (self.days & 0x100) | value as u16,
+      0x0C => {
+        self.days = (self.days & 0xff) | ((value & DAY_HIGH_BIT) as u16) << 8;
+        self.halted = value & HALT == HALT;
+        self.day_carry = value & DAY_CARRY == DAY_CARRY;
+      },
+      _ => unreachable!()
+    }
+    // Games read back what they just wrote without latching, so keep the latched copy in sync
+    self.latched[(register - 0x08) as usize] = self.registers()[(register - 0x08) as usize];
+  }
+
+  // Advances the clock by however many whole seconds of wall clock time have passed
+  pub fn update(&mut self) {
+    let now = now();
+    if now <= self.last_update {
+      return;
+    }
+    let elapsed = now - self.last_update;
+    self.last_update = now;
+    if self.halted {
+      return;
+    }
+    let total = self.seconds as u64 +
+      self.minutes as u64 * 60 +
+      self.hours as u64 * 60 * 60 +
+      self.days as u64 * 60 * 60 * 24 +
+      elapsed;
+    self.seconds = (total % 60) as u8;
+    self.minutes = (total / 60 % 60) as u8;
+    self.hours = (total / (60 * 60) % 24) as u8;
+    let days = total / (60 * 60 * 24);
+    if days > 0x1ff {
+      self.day_carry = true;
+    }
+    self.days = (days & 0x1ff) as u16;
+  }
+
+  pub fn save(&mut self) -> Vec<u8> {
+    self.update();
+    let mut buf = Vec::with_capacity(SAVE_SIZE);
+    for register in self.registers().iter().chain(self.latched.iter()) {
+      buf.extend_from_slice(&[*register, 0, 0, 0]);
+    }
+    for i in 0..8 {
+      buf.push((self.last_update >> (i * 8)) as u8);
+    }
+    buf
+  }
+
+  // Restores from a save trailer and catches up on the time that has passed since
+  pub fn load(&mut self, buf: &[u8]) {
+    let registers = [buf[0], buf[4], buf[8], buf[12], buf[16]];
+    for (i, latched) in self.latched.iter_mut().enumerate() {
+      *latched = buf[20 + i * 4];
+    }
+    self.seconds = registers[0];
+    self.minutes = registers[1];
+    self.hours = registers[2];
+    self.days = ((registers[4] & DAY_HIGH_BIT) as u16) << 8 | registers[3] as u16;
+    self.halted = registers[4] & HALT == HALT;
+    self.day_carry = registers[4] & DAY_CARRY == DAY_CARRY;
+    let timestamp_size = buf.len() - 40;
+    self.last_update = 0;
+    for i in 0..timestamp_size {
+      self.last_update |= (buf[40 + i] as u64) << (i * 8);
+    }
+    self.update();
+  }
+
+  fn registers(&self) -> [u8; 5] {
+    let mut day_high = (self.days >> 8) as u8 & DAY_HIGH_BIT;
+    if self.halted {
+      day_high |= HALT;
+    }
+    if self.day_carry {
+      day_high |= DAY_CARRY;
+    }
+    [self.seconds, self.minutes, self.hours, self.days as u8, day_high]
+  }
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A clock showing day 0x123, 04:05:06, halted so it stays put
+  fn halted_rtc() -> Rtc {
+    let mut rtc = Rtc::new();
+    rtc.write(0x0C, HALT | DAY_HIGH_BIT);
+    rtc.write(0x0B, 0x23);
+    rtc.write(0x0A, 4);
+    rtc.write(0x09, 5);
+    rtc.write(0x08, 6);
+    rtc
+  }
+
+  #[test]
+  fn latches_on_zero_then_one() {
+    let mut rtc = halted_rtc();
+    rtc.seconds = 30;
+    rtc.write_latch(0x01);
+    assert_eq!(rtc.read(0x08), 6);
+    rtc.write_latch(0x00);
+    rtc.write_latch(0x02);
+    rtc.write_latch(0x01);
+    assert_eq!(rtc.read(0x08), 6);
+    rtc.write_latch(0x00);
+    rtc.write_latch(0x01);
+    assert_eq!(rtc.read(0x08), 30);
+  }
+
+  #[test]
+  fn halted_clock_stays_put() {
+    let mut rtc = halted_rtc();
+    rtc.last_update -= 1000;
+    rtc.update();
+    assert_eq!(rtc.registers(), [6, 5, 4, 0x23, HALT | DAY_HIGH_BIT]);
+  }
+
+  #[test]
+  fn day_counter_overflow_sets_carry() {
+    let mut rtc = Rtc::new();
+    rtc.seconds = 59;
+    rtc.minutes = 59;
+    rtc.hours = 23;
+    rtc.days = 0x1ff;
+    rtc.last_update -= 1;
+    rtc.update();
+    assert_eq!(rtc.minutes, 0);
+    assert_eq!(rtc.hours, 0);
+    assert_eq!(rtc.days, 0);
+    assert!(rtc.day_carry);
+  }
+
+  #[test]
+  fn save_round_trip() {
+    let mut rtc = halted_rtc();
+    rtc.write_latch(0x00);
+    rtc.write_latch(0x01);
+    rtc.write(0x08, 7);
+    let buf = rtc.save();
+    assert_eq!(buf.len(), SAVE_SIZE);
+    let mut loaded = Rtc::new();
+    loaded.load(&buf);
+    assert_eq!(loaded.registers(), [7, 5, 4, 0x23, HALT | DAY_HIGH_BIT]);
+    assert_eq!(loaded.latched, rtc.latched);
+    assert_eq!(loaded.last_update, rtc.last_update);
+  }
+
+  #[test]
+  fn loads_short_trailer() {
+    let mut rtc = halted_rtc();
+    let mut buf = rtc.save();
+    buf.truncate(SHORT_SAVE_SIZE);
+    let mut loaded = Rtc::new();
+    loaded.load(&buf);
+    assert_eq!(loaded.registers(), rtc.registers());
+    assert_eq!(loaded.last_update, rtc.last_update & 0xffff_ffff);
+  }
+
+  #[test]
+  fn catches_up_on_load() {
+    let mut rtc = Rtc::new();
+    let mut buf = rtc.save();
+    // Saved an hour ago
+    let then = rtc.last_update - 60 * 60;
+    for i in 0..8 {
+      buf[40 + i] = (then >> (i * 8)) as u8;
+    }
+    let mut loaded = Rtc::new();
+    loaded.load(&buf);
+    assert_eq!(loaded.hours, 1);
+    assert_eq!(loaded.minutes, 0);
+  }
+}
@@ -0,0 +1,167 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+// Transfers on the internal clock run at 8192 Hz, so a bit every 512 cycles
+const CYCLES_PER_BIT: i64 = 512;
+
+const TRANSFER_START: u8 = 0b1000_0000;
+const INTERNAL_CLOCK: u8 = 0b0000_0001;
+
+// Whatever is on the other end of the link cable
+pub trait Link {
+  // We drove the clock and shifted a whole byte out.
+  // Returns the byte that was shifted in from the other end
+  fn exchange(&mut self, value: u8) -> u8;
+
+  // We're waiting on the other end to drive the clock, with value ready to go out.
+  // Returns the byte shifted in once the other end has done a transfer
+  fn poll(&mut self, _value: u8) -> Option<u8> {
+    None
+  }
+
+  // Called as time passes, whether or not there's a transfer going on
+  fn step(&mut self, _cycles: i64) {
+  }
+}
+
+// Nothing plugged in, the input line floats high
+pub struct Disconnected;
+
+impl Link for Disconnected {
+  fn exchange(&mut self, _value: u8) -> u8 {
+    0xff
+  }
+}
+
+// Prints every byte sent. Test ROMs (like Blargg's) write their results out this way
+pub struct StdoutLogger;
+
+impl Link for StdoutLogger {
+  fn exchange(&mut self, value: u8) -> u8 {
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    let _ = handle.write_all(&[value]);
+    let _ = handle.flush();
+    0xff
+  }
+}
+
+struct Port {
+  // What this end will shift out when the other end drives the clock
+  waiting: Option<u8>,
+  // What the other end shifted in to us, not yet picked up
+  received: Option<u8>
+}
+
+// A cable between two emulators running in the same thread
+pub struct Cable {
+  ports: [Port; 2]
+}
+
+// One end of a Cable
+pub struct CablePort {
+  cable: Rc<RefCell<Cable>>,
+  side: usize
+}
+
+impl Cable {
+  pub fn new() -> (CablePort, CablePort) {
+    let cable = Rc::new(RefCell::new(Cable {
+      ports: [Port { waiting: None, received: None }, Port { waiting: None, received: None }]
+    }));
+    (CablePort { cable: cable.clone(), side: 0 }, CablePort { cable: cable, side: 1 })
+  }
+}
+
+impl Link for CablePort {
+  fn exchange(&mut self, value: u8) -> u8 {
+    let mut cable = self.cable.borrow_mut();
+    let other = &mut cable.ports[1 - self.side];
+    match other.waiting.take() {
+      Some(theirs) => {
+        other.received = Some(value);
+        theirs
+      },
+      // The other end isn't ready for a transfer, so there's nothing driving the line
+      None => 0xff
+    }
+  }
+
+  fn poll(&mut self, value: u8) -> Option<u8> {
+    let mut cable = self.cable.borrow_mut();
+    let port = &mut cable.ports[self.side];
+    match port.received.take() {
+      Some(received) => Some(received),
+      None => {
+        port.waiting = Some(value);
+        None
+      }
+    }
+  }
+}
+
+// 0xFF01 - 0xFF02
+pub struct Serial {
+  data: u8,
+  control: u8,
+  // Cycles spent on the transfer in progress
+  cycles: i64,
+  link: Box<Link>
+}
+
+impl Serial {
+  pub fn new() -> Serial {
+    Serial {
+      data: 0,
+      control: 0,
+      cycles: 0,
+      link: Box::new(Disconnected)
+    }
+  }
+
+  pub fn set_link(&mut self, link: Box<Link>) {
+    self.link = link;
+  }
+
+  // Returns true if the serial interrupt should be requested
+  pub fn step(&mut self, cycles: i64) -> bool {
+    self.link.step(cycles);
+    if self.control & TRANSFER_START == 0 {
+      return false;
+    }
+    if self.control & INTERNAL_CLOCK == INTERNAL_CLOCK {
+      self.cycles += cycles;
+      if self.cycles < CYCLES_PER_BIT * 8 {
+        return false;
+      }
+      self.data = self.link.exchange(self.data);
+    } else {
+      match self.link.poll(self.data) {
+        Some(value) => self.data = value,
+        None => return false
+      }
+    }
+    self.control &= !TRANSFER_START;
+    true
+  }
+
+  pub fn read_byte(&self, address: u16) -> u8 {
+    match address {
+      0xFF01 => self.data,
+      0xFF02 => 0b0111_1110 | self.control,
+      _ => unreachable!()
+    }
+  }
+
+  pub fn write_byte(&mut self, address: u16, value: u8) {
+    match address {
+      0xFF01 => self.data = value,
+      0xFF02 => {
+        self.control = value & (TRANSFER_START | INTERNAL_CLOCK);
+        self.cycles = 0;
+      },
+      _ => unreachable!()
+    }
+  }
+}
@@ -0,0 +1,272 @@
+// Super Game Boy. Games talk to it by sending packets bit by bit through P1 (0xFF00),
+// and bulk data by putting it on screen and asking the SGB to copy what it sees
+
+pub const BORDER_WIDTH: u32 = 256;
+pub const BORDER_HEIGHT: u32 = 224;
+// Where the game screen sits inside the border
+pub const SCREEN_X: u32 = 48;
+pub const SCREEN_Y: u32 = 40;
+
+const PACKET_SIZE: usize = 16;
+// The screen is split into 8x8 cells, each of which uses one of the 4 palettes
+const CELLS_WIDE: usize = 20;
+const CELLS_HIGH: usize = 18;
+const TRANSFER_SIZE: usize = 0x1000;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const MASK_EN: u8 = 0x17;
+
+// P1 lines, low means that line is being pulsed
+const P14: u8 = 0b0001_0000;
+const P15: u8 = 0b0010_0000;
+
+pub struct Sgb {
+  // Packet being received
+  packet: [u8; PACKET_SIZE],
+  bit: usize,
+  receiving: bool,
+  // Packets received so far for a command that spans more than one
+  packets: Vec<u8>,
+  // Bits 4 and 5 of the last write to P1
+  last_lines: u8,
+  // 15 bit BGR colours. Colour 0 of palette 0 is shared by all of them
+  palettes: [[u16; 4]; 4],
+  attributes: [u8; CELLS_WIDE * CELLS_HIGH],
+  // 512 palettes of 4 colours, loaded with PAL_TRN for PAL_SET to pick from
+  system_palettes: Vec<u8>,
+  // 256 4 bit SNES tiles, 32 bytes each
+  border_tiles: Vec<u8>,
+  // 32x28 entries of tile number, palette and flips
+  border_map: Vec<u8>,
+  // Palettes 4 - 7, 16 colours each
+  border_palettes: [u16; 64],
+  players: u8,
+  player: u8
+}
+
+impl Sgb {
+  pub fn new() -> Sgb {
+    Sgb {
+      packet: [0; PACKET_SIZE],
+      bit: 0,
+      receiving: false,
+      packets: Vec::new(),
+      last_lines: P14 | P15,
+      palettes: [[0x7fff, 0x56b5, 0x294a, 0x0000]; 4],
+      attributes: [0; CELLS_WIDE * CELLS_HIGH],
+      system_palettes: vec![0; TRANSFER_SIZE],
+      border_tiles: vec![0; TRANSFER_SIZE * 2],
+      border_map: vec![0; 32 * 28 * 2],
+      border_palettes: [0; 64],
+      players: 1,
+      player: 0
+    }
+  }
+
+  // Watches writes to P1 for packets. Memory is needed for commands that copy from VRAM
+  pub fn write_p1(&mut self, value: u8, memory: &[u8; 65536]) {
+    let lines = value & (P14 | P15);
+    let last_lines = self.last_lines;
+    self.last_lines = lines;
+    if lines == 0 {
+      // Both lines low resets, ready for a new packet
+      self.receiving = true;
+      self.bit = 0;
+      self.packet = [0; PACKET_SIZE];
+      return;
+    }
+    if !self.receiving {
+      // With multiplayer on, each time P15 goes back up moves on to the next controller,
+      // which is once per joypad read for the usual way of reading it
+      if self.players > 1 && last_lines & P15 == 0 && lines & P15 == P15 {
+        self.player = (self.player + 1) % self.players;
+      }
+      return;
+    }
+    // A bit is a pulse of one line, P14 for a 0 and P15 for a 1
+    if last_lines != P14 | P15 || lines == P14 | P15 {
+      return;
+    }
+    let bit = if lines == P14 { 1 } else { 0 };
+    if self.bit < PACKET_SIZE * 8 {
+      self.packet[self.bit / 8] |= bit << (self.bit % 8);
+      self.bit += 1;
+      return;
+    }
+    // The 129th bit is a stop bit
+    self.receiving = false;
+    let packet = self.packet;
+    self.packets.extend_from_slice(&packet);
+    let length = (self.packets[0] & 0b111) as usize;
+    if self.packets.len() >= ::std::cmp::max(length, 1) * PACKET_SIZE {
+      let data = ::std::mem::replace(&mut self.packets, Vec::new());
+      self.run_command(&data, memory);
+    }
+  }
+
+  // Reads of P1 say which controller is selected when neither button group is
+  pub fn read_p1(&self, value: u8) -> u8 {
+    if self.players == 1 {
+      return value;
+    }
+    if value & (P14 | P15) == P14 | P15 {
+      (value & 0xf0) | (0x0f - self.player)
+    } else if self.player != 0 {
+      // Nothing is plugged in to the other ports
+      value | 0x0f
+    } else {
+      value
+    }
+  }
+
+  fn run_command(&mut self, data: &[u8], memory: &[u8; 65536]) {
+    match data[0] >> 3 {
+      PAL01 => self.set_palette_pair(data, 0, 1),
+      PAL23 => self.set_palette_pair(data, 2, 3),
+      PAL03 => self.set_palette_pair(data, 0, 3),
+      PAL12 => self.set_palette_pair(data, 1, 2),
+      ATTR_BLK => self.attribute_blocks(data),
+      PAL_SET => {
+        for palette in 0..4 {
+          let number = (read_u16(data, 1 + palette * 2) & 0x1ff) as usize;
+          for color in 0..4 {
+            self.palettes[palette][color] = read_u16(&self.system_palettes, number * 8 + color * 2);
+          }
+        }
+      },
+      PAL_TRN => self.system_palettes = vram_transfer(memory),
+      CHR_TRN => {
+        let start = (data[1] & 0x01) as usize * TRANSFER_SIZE;
+        self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(&vram_transfer(memory));
+      },
+      PCT_TRN => {
+        let transfer = vram_transfer(memory);
+        let map_size = self.border_map.len();
+        self.border_map.copy_from_slice(&transfer[..map_size]);
+        for (i, colour) in self.border_palettes.iter_mut().enumerate() {
+          *colour = read_u16(&transfer, 0x800 + i * 2);
+        }
+      },
+      MLT_REQ => {
+        self.players = match data[1] & 0b11 {
+          1 => 2,
+          3 => 4,
+          _ => 1
+        };
+        self.player = 0;
+      },
+      // Games blank the screen while they put transfer data on it, which we don't bother with
+      MASK_EN => (),
+      command => debug!("Unsupported SGB command {:#04x}", command)
+    }
+  }
+
+  // PAL01, PAL23, PAL03 and PAL12 all set colour 0 and then 3 colours each for two palettes
+  fn set_palette_pair(&mut self, data: &[u8], first: usize, second: usize) {
+    self.palettes[0][0] = read_u16(data, 1);
+    for color in 1..4 {
+      self.palettes[first][color] = read_u16(data, 1 + color * 2);
+      self.palettes[second][color] = read_u16(data, 7 + color * 2);
+    }
+  }
+
+  fn attribute_blocks(&mut self, data: &[u8]) {
+    let count = ::std::cmp::min(data[1] as usize, (data.len() - 2) / 6);
+    for block in data[2..2 + count * 6].chunks(6) {
+      let control = block[0] & 0b111;
+      let inside = block[1] & 0b11;
+      let mut border = (block[1] >> 2) & 0b11;
+      let outside = (block[1] >> 4) & 0b11;
+      // Setting only the inside or only the outside does the border along with it
+      let mut change_border = control & 0b010 != 0;
+      if control == 0b001 {
+        border = inside;
+        change_border = true;
+      } else if control == 0b100 {
+        border = outside;
+        change_border = true;
+      }
+      let (left, top, right, bottom) = (block[2] as usize, block[3] as usize, block[4] as usize, block[5] as usize);
+      for y in 0..CELLS_HIGH {
+        for x in 0..CELLS_WIDE {
+          let within = x >= left && x <= right && y >= top && y <= bottom;
+          let on_edge = within && (x == left || x == right || y == top || y == bottom);
+          if !within && control & 0b100 != 0 {
+            self.attributes[y * CELLS_WIDE + x] = outside;
+          } else if on_edge && change_border {
+            self.attributes[y * CELLS_WIDE + x] = border;
+          } else if within && !on_edge && control & 0b001 != 0 {
+            self.attributes[y * CELLS_WIDE + x] = inside;
+          }
+        }
+      }
+    }
+  }
+
+  // The colour of a shade (after BGP/OBP) at a point on the game screen
+  pub fn colour(&self, x: u8, y: u8, shade: u8) -> u16 {
+    if shade == 0 {
+      return self.palettes[0][0];
+    }
+    let palette = self.attributes[(y as usize / 8) * CELLS_WIDE + x as usize / 8];
+    self.palettes[palette as usize][shade as usize]
+  }
+
+  // The colour of the border at a point, where transparent shows the backdrop
+  pub fn border_colour(&self, x: u32, y: u32) -> u16 {
+    let entry_index = ((y / 8) * 32 + x / 8) as usize * 2;
+    let entry = read_u16(&self.border_map, entry_index);
+    let tile = (entry & 0xff) as usize;
+    let palette = ((entry >> 10) & 0b111) as usize;
+    let mut column = x % 8;
+    let mut row = (y % 8) as usize;
+    if entry & 0x4000 != 0 {
+      column = 7 - column;
+    }
+    if entry & 0x8000 != 0 {
+      row = 7 - row;
+    }
+    // SNES tiles keep bitplanes 0 and 1 together, then 2 and 3
+    let tile_data = &self.border_tiles[tile * 32..tile * 32 + 32];
+    let bit = 7 - column;
+    let mut color = 0;
+    for plane in 0..4 {
+      let byte = tile_data[(plane / 2) * 16 + row * 2 + plane % 2];
+      color |= ((byte >> bit) & 1) << plane;
+    }
+    if color == 0 || palette < 4 {
+      return self.palettes[0][0];
+    }
+    self.border_palettes[(palette - 4) * 16 + color as usize]
+  }
+}
+
+// The SGB copies the first 4KB of what's on screen, taking tiles in the order the background shows them
+fn vram_transfer(memory: &[u8; 65536]) -> Vec<u8> {
+  let control = memory[0xff40];
+  let tile_map = if control & 0b0000_1000 != 0 { 0x9c00 } else { 0x9800 };
+  let mut data = Vec::with_capacity(TRANSFER_SIZE);
+  for i in 0..TRANSFER_SIZE / 16 {
+    let index = memory[tile_map + (i / CELLS_WIDE) * 32 + i % CELLS_WIDE];
+    let tile = if control & 0b0001_0000 != 0 {
+      0x8000 + index as usize * 16
+    } else {
+      (0x9000 + index as i8 as isize * 16) as usize
+    };
+    data.extend_from_slice(&memory[tile..tile + 16]);
+  }
+  data
+}
+
+fn read_u16(data: &[u8], index: usize) -> u16 {
+  (data[index + 1] as u16) << 8 | data[index] as u16
+}
@@ -0,0 +1,221 @@
+// Which bit of the internal divider clocks TIMA, indexed by the TAC clock select bits
+// (4096 Hz, 262144 Hz, 65536 Hz, 16384 Hz)
+const TAC_BITS: [u16; 4] = [1 << 9, 1 << 3, 1 << 5, 1 << 7];
+const TAC_ENABLE: u8 = 0b100;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Reload {
+  None,
+  // TIMA overflowed and reads 0 for a cycle before being reloaded
+  Pending,
+  // TIMA was reloaded from TMA this cycle
+  Done
+}
+
+// 0xFF04 - 0xFF07
+pub struct Timer {
+  // DIV is the upper 8 bits of this
+  divider: u16,
+  tima: u8,
+  tma: u8,
+  tac: u8,
+  reload: Reload
+}
+
+impl Timer {
+  pub fn new() -> Timer {
+    Timer {
+      divider: 0,
+      tima: 0,
+      tma: 0,
+      tac: 0,
+      reload: Reload::None
+    }
+  }
+
+  // Returns true if the timer interrupt should be requested
+  pub fn step(&mut self, cycles: i64) -> bool {
+    let mut interrupt = false;
+    // The timer is clocked once per M-cycle
+    for _ in 0..cycles / 4 {
+      match self.reload {
+        Reload::Pending => {
+          self.tima = self.tma;
+          self.reload = Reload::Done;
+          interrupt = true;
+        },
+        Reload::Done => {
+          self.reload = Reload::None;
+        },
+        Reload::None => ()
+      }
+      let old_bit = self.timer_bit();
+      self.divider = self.divider.wrapping_add(4);
+      if old_bit && !self.timer_bit() {
+        self.increment_tima();
+      }
+    }
+    interrupt
+  }
+
+  // Only for starting up in the state the boot ROM leaves things, the CPU can only ever reset it
+  pub fn set_divider(&mut self, divider: u16) {
+    self.divider = divider;
+  }
+
+  pub fn read_byte(&self, address: u16) -> u8 {
+    match address {
+      0xFF04 => (self.divider >> 8) as u8,
+      0xFF05 => self.tima,
+      0xFF06 => self.tma,
+      0xFF07 => 0b1111_1000 | self.tac,
+      _ => unreachable!()
+    }
+  }
+
+  pub fn write_byte(&mut self, address: u16, value: u8) {
+    match address {
+      0xFF04 => {
+        // TIMA is clocked by a falling edge, so resetting the divider can tick it
+        let old_bit = self.timer_bit();
+        self.divider = 0;
+        if old_bit {
+          self.increment_tima();
+        }
+      },
+      0xFF05 => {
+        match self.reload {
+          // Writing during the delay cancels the reload
+          Reload::Pending => {
+            self.tima = value;
+            self.reload = Reload::None;
+          },
+          // Writing on the cycle TMA is loaded is ignored
+          Reload::Done => (),
+          Reload::None => self.tima = value
+        }
+      },
+      0xFF06 => {
+        self.tma = value;
+        // Writing on the cycle TMA is loaded gets loaded too
+        if self.reload == Reload::Done {
+          self.tima = value;
+        }
+      },
+      0xFF07 => {
+        // Disabling the timer or changing frequency can produce a falling edge
+        let old_bit = self.timer_bit();
+        self.tac = value & 0b111;
+        if old_bit && !self.timer_bit() {
+          self.increment_tima();
+        }
+      },
+      _ => unreachable!()
+    }
+  }
+
+  fn timer_bit(&self) -> bool {
+    self.tac & TAC_ENABLE == TAC_ENABLE && self.divider & TAC_BITS[(self.tac & 0b11) as usize] != 0
+  }
+
+  fn increment_tima(&mut self) {
+    let (value, overflow) = self.tima.overflowing_add(1);
+    self.tima = value;
+    if overflow {
+      self.reload = Reload::Pending;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Enabled at 262144 Hz, so TIMA ticks every 16 cycles on the falling edge of divider bit 3
+  fn timer() -> Timer {
+    let mut timer = Timer::new();
+    timer.write_byte(0xFF07, 0b101);
+    timer
+  }
+
+  #[test]
+  fn counts() {
+    let mut timer = timer();
+    timer.step(12);
+    assert_eq!(timer.read_byte(0xFF05), 0);
+    timer.step(4);
+    assert_eq!(timer.read_byte(0xFF05), 1);
+    timer.step(64);
+    assert_eq!(timer.read_byte(0xFF05), 5);
+  }
+
+  #[test]
+  fn disabled() {
+    let mut timer = Timer::new();
+    timer.write_byte(0xFF07, 0b001);
+    timer.step(1024);
+    assert_eq!(timer.read_byte(0xFF05), 0);
+    assert_eq!(timer.read_byte(0xFF04), 4);
+  }
+
+  #[test]
+  fn div_reset_falling_edge() {
+    let mut timer = timer();
+    timer.step(8);
+    timer.write_byte(0xFF04, 0x12);
+    assert_eq!(timer.read_byte(0xFF05), 1);
+    assert_eq!(timer.read_byte(0xFF04), 0);
+    // The bit is clear this time, so no tick
+    timer.step(4);
+    timer.write_byte(0xFF04, 0x12);
+    assert_eq!(timer.read_byte(0xFF05), 1);
+  }
+
+  #[test]
+  fn tac_falling_edge() {
+    let mut timer = timer();
+    timer.step(8);
+    // Disabling the timer ticks it
+    timer.write_byte(0xFF07, 0b001);
+    assert_eq!(timer.read_byte(0xFF05), 1);
+    timer.write_byte(0xFF07, 0b101);
+    // So does switching to a bit that's clear
+    timer.write_byte(0xFF07, 0b100);
+    assert_eq!(timer.read_byte(0xFF05), 2);
+  }
+
+  #[test]
+  fn reload_is_delayed() {
+    let mut timer = timer();
+    timer.write_byte(0xFF05, 0xff);
+    timer.write_byte(0xFF06, 0x42);
+    assert!(!timer.step(16));
+    assert_eq!(timer.read_byte(0xFF05), 0x00);
+    assert!(timer.step(4));
+    assert_eq!(timer.read_byte(0xFF05), 0x42);
+  }
+
+  #[test]
+  fn tima_write_cancels_reload() {
+    let mut timer = timer();
+    timer.write_byte(0xFF05, 0xff);
+    timer.write_byte(0xFF06, 0x42);
+    timer.step(16);
+    timer.write_byte(0xFF05, 0x10);
+    assert!(!timer.step(4));
+    assert_eq!(timer.read_byte(0xFF05), 0x10);
+  }
+
+  #[test]
+  fn writes_on_reload_cycle() {
+    let mut timer = timer();
+    timer.write_byte(0xFF05, 0xff);
+    timer.write_byte(0xFF06, 0x42);
+    timer.step(20);
+    // TIMA writes are ignored, TMA writes go through to TIMA too
+    timer.write_byte(0xFF05, 0x10);
+    assert_eq!(timer.read_byte(0xFF05), 0x42);
+    timer.write_byte(0xFF06, 0x24);
+    assert_eq!(timer.read_byte(0xFF05), 0x24);
+  }
+}
@@ -0,0 +1,126 @@
+use apu::SampleSink;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+// 16 bit PCM
+pub struct WavWriter {
+  file: BufWriter<File>,
+  data_size: u32
+}
+
+impl WavWriter {
+  pub fn create(path: &Path, channels: u16, sample_rate: u32) -> io::Result<WavWriter> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let block_align = channels * 2;
+    file.write_all(b"RIFF")?;
+    // The sizes get filled in once we know them
+    write_u32(&mut file, 0)?;
+    file.write_all(b"WAVEfmt ")?;
+    write_u32(&mut file, 16)?;
+    write_u16(&mut file, 1)?; // PCM
+    write_u16(&mut file, channels)?;
+    write_u32(&mut file, sample_rate)?;
+    write_u32(&mut file, sample_rate * block_align as u32)?;
+    write_u16(&mut file, block_align)?;
+    write_u16(&mut file, 16)?;
+    file.write_all(b"data")?;
+    write_u32(&mut file, 0)?;
+    Ok(WavWriter {
+      file: file,
+      data_size: 0
+    })
+  }
+
+  pub fn write_sample(&mut self, value: f32) -> io::Result<()> {
+    let value = (value.max(-1.0).min(1.0) * ::std::i16::MAX as f32) as i16;
+    write_u16(&mut self.file, value as u16)?;
+    self.data_size += 2;
+    Ok(())
+  }
+
+  // Fills in the sizes in the header
+  pub fn finish(&mut self) -> io::Result<()> {
+    self.file.seek(SeekFrom::Start(4))?;
+    write_u32(&mut self.file, 36 + self.data_size)?;
+    self.file.seek(SeekFrom::Start(40))?;
+    write_u32(&mut self.file, self.data_size)?;
+    self.file.flush()
+  }
+}
+
+// Writes the stereo mix to a WAV file, and optionally each channel to its own mono file
+// next to it (out.wav, out_ch1.wav, ... out_ch4.wav)
+pub struct WavCapture {
+  mix: WavWriter,
+  channels: Vec<WavWriter>,
+  // Samples come in without a way to report failure, so hold on to the first one for finish
+  error: Option<io::Error>
+}
+
+impl WavCapture {
+  pub fn create(path: &Path, separate_channels: bool) -> io::Result<WavCapture> {
+    let mix = WavWriter::create(path, 2, SAMPLE_RATE)?;
+    let mut channels = Vec::new();
+    if separate_channels {
+      for i in 1..5 {
+        channels.push(WavWriter::create(&channel_path(path, i), 1, SAMPLE_RATE)?);
+      }
+    }
+    Ok(WavCapture {
+      mix: mix,
+      channels: channels,
+      error: None
+    })
+  }
+
+  fn write(&mut self, left: f32, right: f32, channels: &[f32; 4]) -> io::Result<()> {
+    self.mix.write_sample(left)?;
+    self.mix.write_sample(right)?;
+    for (writer, &sample) in self.channels.iter_mut().zip(channels.iter()) {
+      writer.write_sample(sample)?;
+    }
+    Ok(())
+  }
+}
+
+impl SampleSink for WavCapture {
+  fn sample_rate(&self) -> u32 {
+    SAMPLE_RATE
+  }
+
+  fn push(&mut self, left: f32, right: f32, channels: &[f32; 4]) {
+    if self.error.is_some() {
+      return;
+    }
+    if let Err(e) = self.write(left, right, channels) {
+      self.error = Some(e);
+    }
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    if let Some(e) = self.error.take() {
+      return Err(e);
+    }
+    self.mix.finish()?;
+    for writer in self.channels.iter_mut() {
+      writer.finish()?;
+    }
+    Ok(())
+  }
+}
+
+fn channel_path(path: &Path, channel: u8) -> PathBuf {
+  let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+  path.with_file_name(format!("{}_ch{}.wav", stem, channel))
+}
+
+fn write_u16<W: Write>(writer: &mut W, value: u16) -> io::Result<()> {
+  writer.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+  writer.write_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+}